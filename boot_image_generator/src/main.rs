@@ -9,7 +9,7 @@ use std::{
 
 use boot_fs::FileHeader;
 
-const DRIVERS: &'static [&str] = &["file_system", "libpci.a"];
+const DRIVERS: &'static [&str] = &["file_system", "libpci.so", "libhello.a"];
 const DRIVER_PATH: &str = "D:\\Developement\\Projects\\RustKernel\\target\\driver_target\\debug";
 
 const CDRIVERS: &'static [&str] = &["driver"];
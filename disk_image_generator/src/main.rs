@@ -13,10 +13,17 @@ mod iso;
 
 const IN_FILES: &[&str] = &[
     "D:\\Developement\\Projects\\RustKernel\\target\\x86_64-unknown-uefi\\debug\\kernel_loader.efi",
+    "misc/boot.cfg",
+    // Copy of a boot image that is known to work, kept to fall back to from the menu
+    "misc/boot/known_good.bin",
+    "boot_image_generator/boot_image.bin",
+];
+const OUT_FILES: &[&str] = &[
+    "efi/boot/bootx64.efi",
+    "efi/boot/boot.cfg",
+    "efi/boot/btimg.bin",
+    "efi/boot/test.bin",
 ];
-// "D:\\Developement\\Projects\\RustKernel\\boot_image_generator\\boot_image.bin"
-// "efi/boot/btimg.bin"
-const OUT_FILES: &[&str] = &["efi/boot/bootx64.efi"];
 
 fn main() -> io::Result<()> {
     env_logger::init();
@@ -9,4 +9,4 @@ impl DriverCore for FileSystem {
     fn uninit() {}
 }
 
-driver::driver!();
+driver::driver!(FileSystem);
@@ -0,0 +1,16 @@
+#![no_std]
+use driver::DriverCore;
+
+struct Hello;
+
+impl DriverCore for Hello {
+    fn init() {
+        driver::kapi::log("hello driver loaded\n");
+    }
+
+    fn uninit() {
+        driver::kapi::log("hello driver unloaded\n");
+    }
+}
+
+driver::driver!(Hello);
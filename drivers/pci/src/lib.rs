@@ -1,2 +1,14 @@
 #![no_std]
-driver::driver!();
+use driver::DriverCore;
+
+struct Pci;
+
+impl DriverCore for Pci {
+    fn init() {
+        driver::kapi::log("pci driver loaded\n");
+    }
+
+    fn uninit() {}
+}
+
+driver::driver!(Pci);
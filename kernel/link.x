@@ -1,12 +1,31 @@
+/* Must match common::memory_regions::KERNEL_CODE */
 MEMORY
 {
   RAM : ORIGIN = 0x20000000000, LENGTH = 8000000000K
 }
 
+/* Each output section starts on its own page so the loader can give text, rodata and data
+ * different page permissions */
 SECTIONS
 {
-  .text :
+  .text : ALIGN(4K)
   {
     *(.text .text.*);
   } > RAM
-}
\ No newline at end of file
+
+  .rodata : ALIGN(4K)
+  {
+    *(.rodata .rodata.*);
+  } > RAM
+
+  .data : ALIGN(4K)
+  {
+    *(.data .data.*);
+  } > RAM
+
+  .bss : ALIGN(4K)
+  {
+    *(.bss .bss.*);
+    *(COMMON);
+  } > RAM
+}
@@ -0,0 +1,206 @@
+use alloc::vec::Vec;
+use common::{
+    elf::{Header, NoteHeader, ProgramHeader, ProgramHeaderFlags, SegmentType},
+    kprintln, memory_regions,
+    process::Process,
+    serial::SerialPort,
+    vma::{Backing, Protection, PAGE_SIZE},
+    x86_64::{
+        registers::{
+            control::Cr3,
+            model_specific::{FsBase, GsBase},
+        },
+        structures::paging::Translate,
+        VirtAddr,
+    },
+};
+
+use crate::{
+    interrupts::{CpuSnapshot, InterruptStackFrame},
+    process_manager, syscall,
+};
+
+/// Core files go out raw on COM2 so they don't mix with the log on COM1. Run QEMU with a second
+/// `-serial file:core.elf` and open it with `gdb <program> core.elf`.
+const CORE_PORT: u16 = 0x2F8;
+
+pub const SIGSEGV: i32 = 11;
+
+static ZERO_PAGE: [u8; PAGE_SIZE as usize] = [0; PAGE_SIZE as usize];
+
+/// The firmware only sets up COM1
+pub fn init() {
+    SerialPort::new(CORE_PORT);
+}
+
+/// `struct elf_prstatus` as gdb expects it in an `NT_PRSTATUS` note
+#[repr(C)]
+struct PrStatus {
+    signal: i32,
+    code: i32,
+    errno: i32,
+    current_signal: i16,
+    _pad: i16,
+    pending: u64,
+    held: u64,
+    pid: i32,
+    ppid: i32,
+    pgrp: i32,
+    sid: i32,
+    times: [u64; 8],
+    /// `struct user_regs_struct`
+    registers: [u64; 27],
+    fp_valid: i32,
+    _pad2: i32,
+}
+
+struct Segment {
+    start: u64,
+    end: u64,
+    protection: Protection,
+}
+
+fn bytes<T>(value: &T) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(value as *const T as *const u8, core::mem::size_of::<T>()) }
+}
+
+/// Every area of the process except device memory, reading that could have side effects
+fn user_segments(process: &Process) -> Vec<Segment> {
+    process
+        .vmas
+        .iter()
+        .filter(|vma| !matches!(vma.backing, Backing::Device(_)))
+        .map(|vma| Segment {
+            start: vma.start,
+            end: vma.end,
+            protection: vma.protection,
+        })
+        .collect()
+}
+
+/// Writes an ELF core file of the faulting user process: one `PT_LOAD` per area and a `PT_NOTE`
+/// with the registers at the time of the fault. Runs in the kernel address space because the
+/// process doesn't map the kernel heap.
+pub fn write(stack_frame: &InterruptStackFrame, snapshot: &CpuSnapshot, signal: i32) {
+    let (process_cr3, flags) = Cr3::read();
+    unsafe { Cr3::write(syscall::kernel_address_space(), flags) };
+
+    if let Some(process) = process_manager::current() {
+        write_process(process, stack_frame, snapshot, signal);
+    }
+
+    unsafe { Cr3::write(process_cr3, flags) };
+}
+
+fn write_process(
+    process: &mut Process,
+    stack_frame: &InterruptStackFrame,
+    snapshot: &CpuSnapshot,
+    signal: i32,
+) {
+    let segments = user_segments(process);
+
+    let mut status: PrStatus = unsafe { core::mem::zeroed() };
+    status.signal = signal;
+    status.current_signal = signal as i16;
+    status.pid = process.id as i32;
+    status.registers = [
+        snapshot.r15,
+        snapshot.r14,
+        snapshot.r13,
+        snapshot.r12,
+        snapshot.rbp,
+        snapshot.rbx,
+        snapshot.r11,
+        snapshot.r10,
+        snapshot.r9,
+        snapshot.r8,
+        snapshot.rax,
+        snapshot.rcx,
+        snapshot.rdx,
+        snapshot.rsi,
+        snapshot.rdi,
+        u64::MAX, // orig_rax, not in a syscall
+        stack_frame.instruction_pointer.as_u64(),
+        stack_frame.code_segment,
+        stack_frame.cpu_flags,
+        stack_frame.stack_pointer.as_u64(),
+        stack_frame.stack_segment,
+        FsBase::read().as_u64(),
+        GsBase::read().as_u64(),
+        0,
+        0,
+        0,
+        0,
+    ];
+
+    let name = *b"CORE\0\0\0\0";
+    let note = NoteHeader {
+        name_size: 5,
+        desc_size: core::mem::size_of::<PrStatus>() as u32,
+        note_type: NoteHeader::PRSTATUS,
+    };
+    let note_size = core::mem::size_of::<NoteHeader>() + name.len() + core::mem::size_of::<PrStatus>();
+
+    let header = Header::core(segments.len() as u16 + 1);
+    let headers_size = core::mem::size_of::<Header>()
+        + (segments.len() + 1) * core::mem::size_of::<ProgramHeader>();
+
+    let port = SerialPort::from(CORE_PORT);
+    port.write_raw(bytes(&header));
+
+    let mut offset = (headers_size + note_size) as u64;
+    port.write_raw(bytes(&ProgramHeader::new(
+        SegmentType::Note,
+        0,
+        headers_size as u64,
+        0,
+        note_size as u64,
+        0,
+    )));
+    for segment in &segments {
+        let mut flags = ProgramHeaderFlags::Readable as u32;
+        if segment.protection.contains(Protection::WRITE) {
+            flags |= ProgramHeaderFlags::Writable as u32;
+        }
+        if segment.protection.contains(Protection::EXEC) {
+            flags |= ProgramHeaderFlags::Executable as u32;
+        }
+        let size = segment.end - segment.start;
+        port.write_raw(bytes(&ProgramHeader::new(
+            SegmentType::Load,
+            flags,
+            offset,
+            segment.start,
+            size,
+            size,
+        )));
+        offset += size;
+    }
+
+    port.write_raw(bytes(&note));
+    port.write_raw(&name);
+    port.write_raw(bytes(&status));
+
+    // Pages are read through the physmap, ones that were never touched are zero
+    let mapper = process.get_pt();
+    for segment in &segments {
+        for address in (segment.start..segment.end).step_by(PAGE_SIZE as usize) {
+            match mapper.translate_addr(VirtAddr::new(address)) {
+                Some(phys) => port.write_raw(unsafe {
+                    core::slice::from_raw_parts(
+                        (memory_regions::physmap_base() + phys.as_u64()) as *const u8,
+                        PAGE_SIZE as usize,
+                    )
+                }),
+                None => port.write_raw(&ZERO_PAGE),
+            }
+        }
+    }
+
+    kprintln!(
+        "Core dump of {} segments ({} bytes) written to COM2",
+        segments.len(),
+        offset
+    );
+}
@@ -1,5 +1,6 @@
 use alloc::{string::String, vec::Vec};
 use aml::{resource::Resource, AmlValue};
+use common::slab::{ObjectCache, SlabBox};
 use spin::Mutex;
 
 pub enum DeviceType {
@@ -20,9 +21,14 @@ pub struct Device {
 }
 
 // pub static GLOBAL_DEVICES: Mutex<Vec<Device>> = Mutex::new(Vec::new());
-static mut DEVICES: Vec<Device> = Vec::new();
+static DEVICE_CACHE: ObjectCache<Device> = ObjectCache::new("device");
+static mut DEVICES: Vec<SlabBox<Device>> = Vec::new();
 
 pub fn add_device(device: Device) {
+    let device = match DEVICE_CACHE.alloc(device) {
+        Ok(device) => device,
+        Err(_) => panic!("Unable to allocate device!"),
+    };
     unsafe {
         DEVICES.push(device);
     }
@@ -50,5 +56,5 @@ pub fn search_pci_device(
         }
         _ => false,
     });
-    device
+    device.map(|d| &**d)
 }
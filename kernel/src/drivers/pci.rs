@@ -5,7 +5,11 @@ use aml::{
     AmlName,
 };
 use bit_field::BitField;
-use common::{kprintln, size_tb, x86_64::{PhysAddr, VirtAddr, structures::paging::Size4KiB}};
+use common::{
+    kprintln,
+    mmio::{self, CacheMode, Mmio},
+    x86_64::PhysAddr,
+};
 
 use crate::{
     acpi::{aml::GLOBAL_AML, get_xsdt, mcfg::MCFG, Signature},
@@ -13,7 +17,6 @@ use crate::{
 };
 
 // use super::device::GLOBAL_DEVICES;
-const CONFIG_SPACE: u64= size_tb!(1);
 
 // pub static GLOBAL_PCI: AtomicPtr<PCI> = AtomicPtr::new(core::ptr::null_mut());
 pub static mut GLOBAL_PCI: PCI = PCI::new();
@@ -26,25 +29,19 @@ pub fn init() {
         .expect("Unable to get MCFG!");
     let mcfg = mcfg.get_entry::<MCFG>();
 
-    unsafe {
-        GLOBAL_PCI.mcfg = Some(mcfg);
-        
-        // GLOBAL_PCI.traverse_devices(|segment, bus, device, function| {
-        //     let seg = &mcfg[segment];
-        //     let address: *const u64 = PCI::form_address(seg.address, bus - seg.bus_start, device, function, 0);
-        //     let address = address as u64;
-
-        //     kprintln!("Mapping {:x} - {:x}", address, CONFIG_SPACE + (address - seg.address));
-
-        //     common::mem::map_virt(PhysAddr::new(address), VirtAddr::new(CONFIG_SPACE) + (address - seg.address), 4095);
-        // });
+    /* Config space is mapped once, a segment covers up to 256 buses of 1MiB each. Nothing
+     * refers to the MCFG afterwards, ACPI tables are reclaimed once the kernel is done with them. */
+    for ent in mcfg.iter() {
+        let size = (256 - ent.bus_start as usize) << 20;
+        let config = mmio::ioremap(PhysAddr::new(ent.address as u64), size, CacheMode::Uncached)
+            .expect("Unable to map pci config space!");
+        unsafe {
+            GLOBAL_PCI.segments.push(Segment {
+                config,
+                bus_start: ent.bus_start,
+            });
+        }
     }
-
-
-    // for ent in mcfg.iter() {
-    //     kprintln!("Mapping {:x}", ent.address);
-    //     common::mem::map_phys(PhysAddr::new(ent.address as _), 1).expect("Unable to map pci segment space!");
-    // }
 }
 
 pub fn gather_devices() {
@@ -105,11 +102,11 @@ pub fn gather_devices() {
     }
 }
 
-pub fn get_pci() -> &'static PCI<'static> {
+pub fn get_pci() -> &'static PCI {
     unsafe { &GLOBAL_PCI }
 }
 
-pub fn get_pci_mut() -> &'static mut PCI<'static> {
+pub fn get_pci_mut() -> &'static mut PCI {
     unsafe { &mut GLOBAL_PCI }
 }
 
@@ -155,11 +152,18 @@ impl From<u32> for BAR {
     }
 }
 
-pub struct PCI<'a> {
-    pub(self) mcfg: Option<&'a MCFG<'a>>,
+/// ECAM of a segment group
+struct Segment {
+    config: Mmio<u8>,
+    bus_start: u8,
 }
 
-impl<'a> PCI<'a> {
+pub struct PCI {
+    /// In MCFG order
+    segments: Vec<Segment>,
+}
+
+impl PCI {
     const VENDOR_ID: u16 = 0x00;
     const DEVICE_ID: u16 = 0x02;
     const COMMAND: u16 = 0x04;
@@ -201,8 +205,10 @@ impl<'a> PCI<'a> {
     const INT_PIN: u16 = 0x3D;
     const BRIDGE_CTL: u16 = 0x3E;
 
-    const fn new() -> PCI<'a> {
-        PCI { mcfg: None }
+    const fn new() -> PCI {
+        PCI {
+            segments: Vec::new(),
+        }
     }
 
     fn get_bars(&mut self, segment: u16, bus: u8, device: u8, function: u8) -> Vec<Resource> {
@@ -322,56 +328,43 @@ impl<'a> PCI<'a> {
     }
 
     fn traverse_devices<F: Fn(u16, u8, u8, u8) + Copy>(&self, callback: F) {
-        let iter = self.mcfg.unwrap().iter();
-        for entry in iter.enumerate() {
+        for segment in 0..self.segments.len() {
             for bus in 0..=255 {
                 for device in 0..32 {
-                    self.check_device(entry.0 as _, bus, device, callback);
+                    self.check_device(segment as _, bus, device, callback);
                 }
             }
         }
     }
 
-    fn form_address<T>(&self, address: u64, bus: u8, device: u8, function: u8, offset: u16) -> *const T {
-        let seg = &self.mcfg.unwrap()[0];
-        let address = address
-            + ((bus as u64) << 20
-                | (device as u64) << 15
-                | (function as u64) << 12
-                | (offset as u64) & 0xFFF);
-        let address = address - seg.address + CONFIG_SPACE;
-        address as *const T
+    fn form_address<T>(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> *const T {
+        self.form_address_mut(segment, bus, device, function, offset)
     }
 
-    fn form_address_mut<T>(&self, address: u64, bus: u8, device: u8, function: u8, offset: u16) -> *mut T {
-        let seg = &self.mcfg.unwrap()[0];
-        let address = address
+    fn form_address_mut<T>(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> *mut T {
+        let address = self.segments[segment as usize].config.as_ptr() as u64
             + ((bus as u64) << 20
                 | (device as u64) << 15
                 | (function as u64) << 12
                 | (offset as u64) & 0xFFF);
-        let address = address - seg.address + CONFIG_SPACE;
         address as *mut T
     }
 
     pub fn read_u8(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u8 {
-        let seg = &self.mcfg.unwrap()[segment];
-        let address = self.form_address(seg.address, bus - seg.bus_start, device, function, offset);
-        self.map_address(segment, bus, device, function);
+        let seg = &self.segments[segment as usize];
+        let address = self.form_address(segment, bus - seg.bus_start, device, function, offset);
         unsafe { core::ptr::read_volatile(address) }
     }
 
     pub fn read_u16(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u16 {
-        let seg = &self.mcfg.unwrap()[segment];
-        let address = self.form_address(seg.address, bus - seg.bus_start, device, function, offset);
-        self.map_address(segment, bus, device, function);
+        let seg = &self.segments[segment as usize];
+        let address = self.form_address(segment, bus - seg.bus_start, device, function, offset);
         unsafe { core::ptr::read_volatile(address) }
     }
 
     pub fn read_u32(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u32 {
-        let seg = &self.mcfg.unwrap()[segment];
-        let address = self.form_address(seg.address, bus - seg.bus_start, device, function, offset);
-        self.map_address(segment, bus, device, function);
+        let seg = &self.segments[segment as usize];
+        let address = self.form_address(segment, bus - seg.bus_start, device, function, offset);
         unsafe { core::ptr::read_volatile(address) }
     }
 
@@ -384,10 +377,9 @@ impl<'a> PCI<'a> {
         offset: u16,
         value: u8,
     ) {
-        let seg = &self.mcfg.unwrap()[segment];
+        let seg = &self.segments[segment as usize];
         let address =
-            self.form_address_mut(seg.address, bus - seg.bus_start, device, function, offset);
-        self.map_address(segment, bus, device, function);
+            self.form_address_mut(segment, bus - seg.bus_start, device, function, offset);
         unsafe { core::ptr::write_volatile(address, value) }
     }
 
@@ -400,10 +392,9 @@ impl<'a> PCI<'a> {
         offset: u16,
         value: u16,
     ) {
-        let seg = &self.mcfg.unwrap()[segment];
+        let seg = &self.segments[segment as usize];
         let address =
-            self.form_address_mut(seg.address, bus - seg.bus_start, device, function, offset);
-        self.map_address(segment, bus, device, function);
+            self.form_address_mut(segment, bus - seg.bus_start, device, function, offset);
         unsafe { core::ptr::write_volatile(address, value) }
     }
 
@@ -416,23 +407,11 @@ impl<'a> PCI<'a> {
         offset: u16,
         value: u32,
     ) {
-        let seg = &self.mcfg.unwrap()[segment];
+        let seg = &self.segments[segment as usize];
         let address =
-            self.form_address_mut(seg.address, bus - seg.bus_start, device, function, offset);
-        self.map_address(segment, bus, device, function);
+            self.form_address_mut(segment, bus - seg.bus_start, device, function, offset);
         unsafe { core::ptr::write_volatile(address, value) }
     }
-
-    pub fn map_address(&self, segment: u16,
-        bus: u8,
-        device: u8,
-        function: u8,) {
-
-        let seg = &self.mcfg.unwrap()[segment];
-        let address: *const u64 = self.form_address(seg.address, bus - seg.bus_start, device, function, 0);
-        let address = address as u64;
-        common::mem::map_virt::<Size4KiB>(PhysAddr::new(seg.address as u64), VirtAddr::new(address), 4095);
-    }
 }
 
 pub fn class_str(class: u8, subclass: u8, prog_if: u8) -> &'static str {
@@ -0,0 +1,39 @@
+use core::alloc::Layout;
+
+use common::kprint;
+
+/// Name drivers link against for kernel services. Nothing is loaded for it, imports are resolved
+/// against the functions below.
+pub const KERNEL_LIBRARY: &str = "libkapi.so";
+
+/// Address of a kernel function exported to drivers
+pub fn lookup(name: &str) -> Option<u64> {
+    let address = match name {
+        "kapi_log" => kapi_log as usize,
+        "kapi_alloc" => kapi_alloc as usize,
+        "kapi_free" => kapi_free as usize,
+        _ => return None,
+    };
+    Some(address as u64)
+}
+
+extern "C" fn kapi_log(message: *const u8, len: usize) {
+    let bytes = unsafe { core::slice::from_raw_parts(message, len) };
+    match core::str::from_utf8(bytes) {
+        Ok(message) => kprint!("{}", message),
+        Err(_) => kprint!("<invalid utf-8>"),
+    }
+}
+
+extern "C" fn kapi_alloc(size: usize, align: usize) -> *mut u8 {
+    match Layout::from_size_align(size, align) {
+        Ok(layout) => unsafe { alloc::alloc::alloc(layout) },
+        Err(_) => core::ptr::null_mut(),
+    }
+}
+
+extern "C" fn kapi_free(ptr: *mut u8, size: usize, align: usize) {
+    if let Ok(layout) = Layout::from_size_align(size, align) {
+        unsafe { alloc::alloc::dealloc(ptr, layout) }
+    }
+}
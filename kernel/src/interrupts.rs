@@ -1,11 +1,15 @@
 use alloc::vec::Vec;
 use bit_field::BitField;
 use common::{
-    kprint, kprintln,
+    cpu, kprint, kprintln,
+    mmio::{self, CacheMode, Mmio},
     util::{in8, out8},
-    x86_64::PhysAddr,
+    x86_64::{PhysAddr, VirtAddr},
+};
+use core::{
+    arch::{asm, global_asm},
+    borrow::Borrow,
 };
-use core::{arch::asm, borrow::Borrow};
 use macros::{generate_isrs, set_isrs};
 
 use crate::{
@@ -15,13 +19,14 @@ use crate::{
         Signature, RSDP,
     },
     drivers::keyboard::Keyboard,
-    gdt,
+    coredump, gdt, process_manager, syscall,
+    symbols::Symbolized,
 };
 
 use common::process::{self, SYSCALL_SP, SYSCALL_UMAP, SYSCALL_USP};
 use common::util;
 use common::x86_64::{
-    registers::model_specific::Msr,
+    registers::{control::Cr3, model_specific::Msr},
     structures::idt::{self, InterruptDescriptorTable},
 };
 use lazy_static::lazy_static;
@@ -51,10 +56,10 @@ pub struct CpuSnapshot {
     pub rdi: u64,
     pub rsi: u64,
 
-    pub rax: u64,
-    pub rbx: u64,
-    pub rcx: u64,
     pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
 }
 
 pub use idt::InterruptStackFrame;
@@ -156,8 +161,6 @@ lazy_static! {
     pub static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
         idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.general_protection_fault
-            .set_handler_fn(general_protection_handler);
         idt.non_maskable_interrupt.set_handler_fn(nmi_handler);
         idt.segment_not_present
             .set_handler_fn(segment_not_present_handler);
@@ -166,9 +169,10 @@ lazy_static! {
             idt.double_fault
                 .set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            idt.general_protection_fault
+                .set_handler_addr(VirtAddr::new(general_protection_stub as u64));
             idt.page_fault
-                .set_handler_fn(pagefault_handler)
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+                .set_handler_addr(VirtAddr::new(page_fault_stub as u64));
         }
 
         /* APIC Stuff */
@@ -209,10 +213,12 @@ fn interrupt(stack_frame: &mut idt::InterruptStackFrame, snapshot: &CpuSnapshot,
 }
 
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: idt::InterruptStackFrame) {
+    cpu::clac();
     kprintln!("EXCPETION: BREAKPOINT\n{:#?}\n", stack_frame);
 }
 
 extern "x86-interrupt" fn nmi_handler(stack_frame: idt::InterruptStackFrame) {
+    cpu::clac();
     kprintln!("EXCPETION: NMI\n{:#?}\n", stack_frame);
 }
 
@@ -220,13 +226,108 @@ extern "x86-interrupt" fn segment_not_present_handler(
     stack_frame: idt::InterruptStackFrame,
     e: u64,
 ) {
+    cpu::clac();
 }
 
-extern "x86-interrupt" fn general_protection_handler(
-    stack_frame: idt::InterruptStackFrame,
-    _e: u64,
-) {
-    kprintln!("EXCPETION: GP\n{:#?}\n{}\n", stack_frame, _e);
+/// Error code the CPU pushed for a fault and the frame above it
+#[repr(C)]
+pub struct FaultFrame {
+    pub error_code: u64,
+    pub frame: InterruptStackFrame,
+}
+
+/* Faults that can come from user mode save every register before any Rust code runs, a core
+ * dump needs them as the process left them. The handler gets the snapshot and the fault frame.
+ * AC is cleared first so SMAP stays on even if user mode left it set. */
+global_asm!(
+    "
+    .global general_protection_stub
+general_protection_stub:
+    push rax
+    push rbx
+    lea rax, [rip + general_protection_handler]
+    jmp fault_entry
+
+    .global page_fault_stub
+page_fault_stub:
+    push rax
+    push rbx
+    lea rax, [rip + pagefault_handler]
+    jmp fault_entry
+
+fault_entry:
+    cmp byte ptr [rip + SMAP], 0
+    je 1f
+    clac
+1:
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    push rbp
+
+    mov rdi, rsp
+    lea rsi, [rsp + 15 * 8]
+    mov rbx, rsp
+    and rsp, -16
+    call rax
+    mov rsp, rbx
+
+    pop rbp
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    add rsp, 8
+    iretq
+    "
+);
+
+extern "C" {
+    fn general_protection_stub();
+    fn page_fault_stub();
+}
+
+/// Writes the core dump of a faulting process and stays in the kernel address space, the
+/// diagnostics need the heap and the boot image which the process doesn't map. The process gets
+/// killed afterwards.
+fn leave_user_fault(stack_frame: &InterruptStackFrame, snapshot: &CpuSnapshot) {
+    coredump::write(stack_frame, snapshot, coredump::SIGSEGV);
+    let (_, flags) = Cr3::read();
+    unsafe { Cr3::write(syscall::kernel_address_space(), flags) };
+}
+
+#[no_mangle]
+extern "C" fn general_protection_handler(snapshot: &CpuSnapshot, fault: &mut FaultFrame) {
+    let stack_frame = &fault.frame;
+    if stack_frame.code_segment & 3 == 3 {
+        leave_user_fault(stack_frame, snapshot);
+    }
+
+    kprintln!("EXCPETION: GP\n{:#?}\n{}\n", stack_frame, fault.error_code);
+    kprintln!("At: {}", Symbolized(stack_frame.instruction_pointer.as_u64()));
+    if stack_frame.code_segment & 3 == 3 {
+        process_manager::kill_current(&mut fault.frame);
+        return;
+    }
     loop {}
 }
 
@@ -234,34 +335,53 @@ extern "x86-interrupt" fn double_fault_handler(
     stack_frame: idt::InterruptStackFrame,
     _e: u64,
 ) -> ! {
+    cpu::clac();
     kprintln!("EXCPETION: Double Fault\n{:#?}\n{}\n", stack_frame, _e);
+    kprintln!("At: {}", Symbolized(stack_frame.instruction_pointer.as_u64()));
     loop {}
 }
 
-extern "x86-interrupt" fn pagefault_handler(
-    _stack_frame: idt::InterruptStackFrame,
-    _error_code: idt::PageFaultErrorCode,
-) {
+#[no_mangle]
+extern "C" fn pagefault_handler(snapshot: &CpuSnapshot, fault: &mut FaultFrame) {
     use common::x86_64::registers::control::Cr2;
 
+    let error_code = idt::PageFaultErrorCode::from_bits_truncate(fault.error_code);
+    let stack_frame = &fault.frame;
+
+    /* Faults on lazily mapped user memory return straight to the process */
+    let user = error_code.contains(idt::PageFaultErrorCode::USER_MODE);
+    if user && process_manager::handle_fault(Cr2::read().as_u64(), error_code) {
+        return;
+    }
+
+    if user {
+        leave_user_fault(stack_frame, snapshot);
+    }
+
     kprintln!(
         "EXCPETION: PAGE FAULT\n{:#?}\n{:#?}\n",
-        _stack_frame,
-        _error_code
+        stack_frame,
+        error_code
     );
-    kprintln!("Address: {:?}\n", Cr2::read());
+    kprintln!("Address: {:?}", Cr2::read());
+    kprintln!("At: {}\n", Symbolized(stack_frame.instruction_pointer.as_u64()));
 
-    loop {}
+    if user {
+        process_manager::kill_current(&mut fault.frame);
+        return;
+    }
+    panic!("Page fault in kernel mode at {:?}", Cr2::read());
 }
 
 extern "x86-interrupt" fn lapic_spurious(_stack_frame: idt::InterruptStackFrame) {
+    cpu::clac();
     kprintln!("LAPIC Spurious");
     APIC.lock().send_eoi();
 }
 
 pub struct LocalApic {
     msr: Msr,
-    base: u64,
+    registers: Option<Mmio<u32>>,
 }
 
 impl LocalApic {
@@ -270,13 +390,15 @@ impl LocalApic {
 
         LocalApic {
             msr,
-            base: 0xFEE00000,
+            registers: None,
         }
     }
 
     pub fn init(&mut self) {
         let value = unsafe { self.msr.read() };
-        self.base = value & 0xFFFFFF000;
+        let base = PhysAddr::new(value & 0xFFFFFF000);
+        self.registers =
+            Some(mmio::ioremap(base, 0x400, CacheMode::Uncached).expect("Unable to map local APIC!"));
 
         self.write(LocalApic::SIV, self.read(LocalApic::SIV) | 0x1FF);
 
@@ -312,13 +434,17 @@ impl LocalApic {
     const TIMER_PERIODIC: u32 = 0x20000;
 
     fn write(&mut self, offset: u16, value: u32) {
-        unsafe {
-            core::ptr::write_volatile((self.base + offset as u64) as *mut _, value);
-        }
+        self.registers
+            .as_mut()
+            .expect("Local APIC is not initialized!")
+            .write_at(offset as usize, value);
     }
 
     fn read(&self, offset: u16) -> u32 {
-        unsafe { core::ptr::read_volatile((self.base + offset as u64) as *mut _) }
+        self.registers
+            .as_ref()
+            .expect("Local APIC is not initialized!")
+            .read_at(offset as usize)
     }
 
     pub fn send_eoi(&mut self) {
@@ -403,7 +529,7 @@ impl RedirectionEntry {
 }
 
 pub struct IOApic {
-    base: u64,
+    registers: Option<Mmio<u32>>,
 }
 
 impl IOApic {
@@ -413,7 +539,7 @@ impl IOApic {
     const RED_TABLE: u16 = 0x10;
 
     pub const fn new() -> IOApic {
-        IOApic { base: 0 }
+        IOApic { registers: None }
     }
 
     pub fn init(&mut self) {
@@ -435,14 +561,14 @@ impl IOApic {
             })
             .expect("Unable to find Local Apic in madt!");
 
-        match ioapic {
+        let base = match ioapic {
             Entry::IoApic {
                 io_apic_address, ..
-            } => self.base = *io_apic_address as u64,
-            _ => (),
-        }
-
-        common::mem::map_phys(PhysAddr::new(self.base), 4096);
+            } => PhysAddr::new(*io_apic_address as u64),
+            _ => unreachable!(),
+        };
+        self.registers =
+            Some(mmio::ioremap(base, 0x20, CacheMode::Uncached).expect("Unable to map IO APIC!"));
 
         let mut re = RedirectionEntry::new();
         re.set_vector(0x45);
@@ -450,17 +576,15 @@ impl IOApic {
     }
 
     pub fn write(&mut self, offset: u16, value: u32) {
-        unsafe {
-            core::ptr::write_volatile(self.base as *mut u32, offset as _); // IOREGSEL
-            core::ptr::write_volatile((self.base + 0x10) as *mut u32, value); // IOWIN
-        }
+        let registers = self.registers.as_mut().expect("IO APIC is not initialized!");
+        registers.write_at(0, offset as u32); // IOREGSEL
+        registers.write_at(0x10, value); // IOWIN
     }
 
-    pub fn read(&self, offset: u16) -> u32 {
-        unsafe {
-            core::ptr::write_volatile(self.base as *mut u32, offset as _); // IOREGSEL
-            core::ptr::read_volatile((self.base + 10) as *mut u32) // IOWIN
-        }
+    pub fn read(&mut self, offset: u16) -> u32 {
+        let registers = self.registers.as_mut().expect("IO APIC is not initialized!");
+        registers.write_at(0, offset as u32); // IOREGSEL
+        registers.read_at(0x10) // IOWIN
     }
 
     pub fn write_entry(&mut self, vector: u8, entry: &RedirectionEntry) {
@@ -0,0 +1,538 @@
+//! In-kernel linker for drivers shipped as relocatable objects (`ET_REL`) or `ar` archives of them
+
+use alloc::{collections::BTreeMap, string::String, vec, vec::Vec};
+use common::{
+    elf::{self, ElfFile, FileType, Rela, SectionHeader, Symbol},
+    kprintln, mem,
+    memory_regions,
+    x86_64::{
+        instructions::tlb,
+        structures::paging::{
+            FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB,
+        },
+        VirtAddr,
+    },
+};
+
+use crate::{
+    exports,
+    modules::{ModuleError, DRIVER_INIT, DRIVER_UNINIT},
+};
+
+const ARCHIVE_MAGIC: &[u8] = b"!<arch>\n";
+const ARCHIVE_HEADER_SIZE: usize = 60;
+
+/// `jmp [rip]` followed by the target, lets modules call kernel functions out of rel32 range
+const STUB: [u8; 6] = [0xFF, 0x25, 0x00, 0x00, 0x00, 0x00];
+const STUB_SIZE: u64 = 14;
+
+pub fn is_archive(data: &[u8]) -> bool {
+    data.starts_with(ARCHIVE_MAGIC)
+}
+
+/// Members of a System V / GNU `ar` archive, the symbol and long name tables are skipped
+pub struct Members<'a> {
+    data: &'a [u8],
+    offset: usize,
+    long_names: &'a [u8],
+}
+
+pub fn members(data: &[u8]) -> Members {
+    Members {
+        data,
+        offset: ARCHIVE_MAGIC.len(),
+        long_names: &[],
+    }
+}
+
+impl<'a> Iterator for Members<'a> {
+    type Item = (&'a str, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let header = self
+                .data
+                .get(self.offset..self.offset + ARCHIVE_HEADER_SIZE)?;
+            let field = |range: core::ops::Range<usize>| {
+                core::str::from_utf8(&header[range]).map_or("", |f| f.trim_end())
+            };
+            let name = field(0..16);
+            let size: usize = field(48..58).parse().ok()?;
+
+            let start = self.offset + ARCHIVE_HEADER_SIZE;
+            let body = self.data.get(start..start + size)?;
+            // Members are 2 byte aligned
+            self.offset = (start + size + 1) & !1;
+
+            match name {
+                "/" | "/SYM64/" => continue,
+                "//" => {
+                    self.long_names = body;
+                    continue;
+                }
+                _ => (),
+            }
+
+            let name = match name.strip_prefix('/').and_then(|n| n.parse::<usize>().ok()) {
+                Some(offset) => {
+                    let names = self.long_names.get(offset..).unwrap_or(&[]);
+                    let len = names
+                        .iter()
+                        .position(|&b| b == b'\n')
+                        .unwrap_or(names.len());
+                    core::str::from_utf8(&names[..len]).unwrap_or("")
+                }
+                None => name,
+            };
+            return Some((name.trim_end_matches('/'), body));
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Text,
+    ReadOnly,
+    Data,
+}
+
+impl Kind {
+    fn of(section: &SectionHeader) -> Option<Kind> {
+        if section.flags & SectionHeader::FLAG_ALLOC == 0 {
+            None
+        } else if section.flags & SectionHeader::FLAG_EXECINSTR != 0 {
+            Some(Kind::Text)
+        } else if section.flags & SectionHeader::FLAG_WRITE != 0 {
+            Some(Kind::Data)
+        } else {
+            Some(Kind::ReadOnly)
+        }
+    }
+
+    fn flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT;
+        if *self == Kind::Data {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if *self != Kind::Text && mem::nx_enabled() {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+}
+
+fn align_up(value: u64, align: u64) -> u64 {
+    let align = align.max(1);
+    (value + align - 1) / align * align
+}
+
+/// An object being linked and where each of its sections ended up, 0 for sections that aren't
+/// loaded
+struct Object<'a> {
+    elf: ElfFile<'a>,
+    sections: Vec<u64>,
+    /// Space for `COMMON` symbols, by symbol index
+    commons: BTreeMap<u32, u64>,
+}
+
+/// Image being laid out, offsets are relative to the start of their part
+struct Layout {
+    /// Size of the text, read-only and data parts
+    sizes: [u64; 3],
+    stubs: u64,
+    got: u64,
+}
+
+impl Layout {
+    fn allocate(&mut self, kind: Kind, size: u64, align: u64) -> u64 {
+        let cursor = &mut self.sizes[kind as usize];
+        let offset = align_up(*cursor, align);
+        *cursor = offset + size;
+        offset
+    }
+
+    /// Start of each part, each one starts on a page so it can get its own permissions
+    fn starts(&self, base: u64) -> [u64; 3] {
+        let text = base;
+        let rodata = align_up(text + self.sizes[0], 4096);
+        let data = align_up(rodata + self.sizes[1], 4096);
+        [text, rodata, data]
+    }
+
+    fn size(&self) -> u64 {
+        let [_, _, data] = self.starts(0);
+        align_up(data + self.sizes[2], 4096)
+    }
+}
+
+/// Symbol values resolved while relocating, with the stubs and GOT slots handed out so far
+struct Resolver<'a> {
+    /// Address of every global symbol the module defines and whether it is weak
+    globals: BTreeMap<&'a str, (u64, bool)>,
+    stubs: BTreeMap<u64, u64>,
+    next_stub: u64,
+    got: BTreeMap<u64, u64>,
+    next_got: u64,
+}
+
+impl<'a> Resolver<'a> {
+    fn symbol(&self, object: &Object<'_>, symbol: &Symbol, name: &str) -> Result<u64, ModuleError> {
+        match symbol.section_index {
+            0 => {}
+            Symbol::INDEX_ABS => return Ok(symbol.value),
+            Symbol::INDEX_COMMON => {}
+            index => {
+                if symbol.binding() == Symbol::BIND_LOCAL {
+                    let section = object.sections.get(index as usize).copied().unwrap_or(0);
+                    return Ok(section + symbol.value);
+                }
+            }
+        }
+
+        if let Some(&(address, _)) = self.globals.get(name) {
+            return Ok(address);
+        }
+        if let Some(address) = exports::lookup(name) {
+            return Ok(address);
+        }
+        if symbol.is_weak() {
+            return Ok(0);
+        }
+        Err(ModuleError::UndefinedSymbol(String::from(name)))
+    }
+
+    /// Stub jumping to `target`, created on first use
+    fn stub(&mut self, target: u64) -> u64 {
+        if let Some(&stub) = self.stubs.get(&target) {
+            return stub;
+        }
+        let stub = self.next_stub;
+        self.next_stub += STUB_SIZE;
+        unsafe {
+            core::ptr::copy_nonoverlapping(STUB.as_ptr(), stub as *mut u8, STUB.len());
+            core::ptr::write_unaligned((stub + STUB.len() as u64) as *mut u64, target);
+        }
+        self.stubs.insert(target, stub);
+        stub
+    }
+
+    /// GOT slot holding `target`, created on first use
+    fn got(&mut self, target: u64) -> u64 {
+        if let Some(&slot) = self.got.get(&target) {
+            return slot;
+        }
+        let slot = self.next_got;
+        self.next_got += 8;
+        unsafe { core::ptr::write_unaligned(slot as *mut u64, target) };
+        self.got.insert(target, slot);
+        slot
+    }
+}
+
+fn write_32(address: u64, value: i64, signed: bool) -> Result<(), ModuleError> {
+    let fits = if signed {
+        value >= i32::MIN as i64 && value <= i32::MAX as i64
+    } else {
+        value >= 0 && value <= u32::MAX as i64
+    };
+    if !fits {
+        return Err(ModuleError::Relocation(elf::RelocationError::Overflow(address)));
+    }
+    unsafe { core::ptr::write_unaligned(address as *mut u32, value as u32) };
+    Ok(())
+}
+
+/// Bytes a relocation writes
+fn width(rtype: u32) -> u64 {
+    match rtype {
+        Rela::X86_64_NONE => 0,
+        Rela::X86_64_64 | Rela::X86_64_PC64 => 8,
+        _ => 4,
+    }
+}
+
+/// A linked module and its driver entry points
+pub struct Linked {
+    pub base: u64,
+    pub size: u64,
+    pub init: Option<u64>,
+    pub uninit: Option<u64>,
+}
+
+/// Links the `ET_REL` objects in `files` into one module at a free address in the module region
+pub fn link<'a>(files: impl Iterator<Item = (&'a str, &'a [u8])>) -> Result<Linked, ModuleError> {
+    let mut objects = Vec::new();
+    for (name, data) in files {
+        match ElfFile::parse(data) {
+            Ok(elf) if matches!(elf.header().file_type(), FileType::Relocatable) => {
+                let count = elf.section_headers().count();
+                objects.push(Object {
+                    elf,
+                    sections: vec![0; count],
+                    commons: BTreeMap::new(),
+                });
+            }
+            Ok(_) => kprintln!("Skipping {}, not a relocatable object", name),
+            Err(e) => kprintln!("Skipping {}: {:?}", name, e),
+        }
+    }
+    if objects.is_empty() {
+        return Err(ModuleError::Empty);
+    }
+
+    /* Lay out every allocated section by permission, then the stubs and GOT */
+    let mut layout = Layout {
+        sizes: [0; 3],
+        stubs: 0,
+        got: 0,
+    };
+    let mut stub_count = 0;
+    let mut got_count = 0;
+    let mut offsets = Vec::new();
+    for object in &mut objects {
+        let mut object_offsets = vec![None; object.sections.len()];
+        for (i, section) in object.elf.section_headers().enumerate() {
+            if section.flags & SectionHeader::FLAG_TLS != 0 {
+                return Err(ModuleError::UnsupportedTls);
+            }
+            if let Some(kind) = Kind::of(section) {
+                let offset = layout.allocate(kind, section.size, section.align);
+                object_offsets[i] = Some((kind, offset));
+            }
+
+            for rela in object.elf.section_relocations(section) {
+                match rela.relocation_type() {
+                    Rela::X86_64_PC32 | Rela::X86_64_PLT32 => stub_count += 1,
+                    Rela::X86_64_GOTPCREL | Rela::X86_64_GOTPCRELX | Rela::X86_64_REX_GOTPCRELX => {
+                        got_count += 1
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        let mut common_offsets = BTreeMap::new();
+        if let Some(symbols) = object.elf.symbols() {
+            for (index, symbol) in symbols.iter().enumerate() {
+                if symbol.section_index == Symbol::INDEX_COMMON {
+                    // The value of a common symbol is its alignment
+                    let offset = layout.allocate(Kind::Data, symbol.size, symbol.value);
+                    common_offsets.insert(index as u32, offset);
+                }
+            }
+        }
+        offsets.push((object_offsets, common_offsets));
+    }
+    layout.stubs = layout.allocate(Kind::Text, stub_count * STUB_SIZE, 16);
+    layout.got = layout.allocate(Kind::ReadOnly, got_count * 8, 8);
+
+    let size = layout.size();
+    let base = crate::modules::reserve(size)?;
+    let starts = layout.starts(base);
+
+    for (object, (object_offsets, common_offsets)) in objects.iter_mut().zip(offsets) {
+        for (address, offset) in object.sections.iter_mut().zip(object_offsets) {
+            if let Some((kind, offset)) = offset {
+                *address = starts[kind as usize] + offset;
+            }
+        }
+        for (index, offset) in common_offsets {
+            object.commons.insert(index, starts[Kind::Data as usize] + offset);
+        }
+    }
+
+    load_image(&objects, &layout, base, size).map_err(|e| {
+        crate::modules::release(base, size);
+        e
+    })
+}
+
+/// Maps the image at `base`, copies the sections in and relocates them. The caller releases the
+/// range on error.
+fn load_image(
+    objects: &[Object<'_>],
+    layout: &Layout,
+    base: u64,
+    size: u64,
+) -> Result<Linked, ModuleError> {
+    let starts = layout.starts(base);
+
+    /* Map the whole image writable while it is filled in */
+    let mut mapper = mem::active_offset_page_table(memory_regions::physmap_base());
+    let pages = Page::<Size4KiB>::range(
+        Page::containing_address(VirtAddr::new(base)),
+        Page::containing_address(VirtAddr::new(base + size)),
+    );
+    for page in pages {
+        let frame = mem::charged(mem::Owner::Module)
+            .allocate_frame()
+            .ok_or(ModuleError::OutOfMemory)?;
+        let mapped = unsafe {
+            mapper.map_to(
+                page,
+                frame,
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+                &mut mem::charged(mem::Owner::Module),
+            )
+        };
+        match mapped {
+            Ok(flush) => flush.flush(),
+            Err(_) => {
+                unsafe { mem::charged(mem::Owner::Module).deallocate_frame(frame) };
+                return Err(ModuleError::OutOfMemory);
+            }
+        }
+    }
+    unsafe { core::ptr::write_bytes(base as *mut u8, 0, size as usize) };
+
+    for object in objects {
+        for (section, &address) in object.elf.section_headers().zip(&object.sections) {
+            if address == 0 {
+                continue;
+            }
+            if let Some(data) = object.elf.section_data(section) {
+                unsafe {
+                    core::ptr::copy_nonoverlapping(data.as_ptr(), address as *mut u8, data.len())
+                };
+            }
+        }
+    }
+
+    /* Global symbols of all objects, a strong definition replaces a weak one */
+    let mut resolver = Resolver {
+        globals: BTreeMap::new(),
+        stubs: BTreeMap::new(),
+        next_stub: starts[Kind::Text as usize] + layout.stubs,
+        got: BTreeMap::new(),
+        next_got: starts[Kind::ReadOnly as usize] + layout.got,
+    };
+    for object in objects {
+        let symbols = match object.elf.symbols() {
+            Some(symbols) => symbols,
+            None => continue,
+        };
+        for (index, symbol) in symbols.iter().enumerate() {
+            if symbol.binding() == Symbol::BIND_LOCAL || symbol.is_undefined() {
+                continue;
+            }
+            let address = match symbol.section_index {
+                Symbol::INDEX_ABS => symbol.value,
+                Symbol::INDEX_COMMON => object.commons[&(index as u32)],
+                section => object.sections.get(section as usize).copied().unwrap_or(0) + symbol.value,
+            };
+            let name = match symbols.name(symbol) {
+                Some(name) => name,
+                None => continue,
+            };
+            match resolver.globals.get(name) {
+                Some(&(_, weak)) if !weak || symbol.is_weak() => (),
+                _ => {
+                    resolver.globals.insert(name, (address, symbol.is_weak()));
+                }
+            }
+        }
+    }
+
+    /* Apply the relocations of every loaded section */
+    for object in objects {
+        let symbols = match object.elf.symbols() {
+            Some(symbols) => symbols,
+            None => continue,
+        };
+        for section in object.elf.section_headers() {
+            let target = object.sections.get(section.info as usize).copied().unwrap_or(0);
+            if section.section_type != SectionHeader::RELA || target == 0 {
+                continue;
+            }
+            let target_size = object
+                .elf
+                .section_headers()
+                .nth(section.info as usize)
+                .map_or(0, |target| target.size);
+
+            for rela in object.elf.section_relocations(section) {
+                let index = rela.symbol();
+                let symbol = symbols
+                    .get(index)
+                    .ok_or(ModuleError::Relocation(elf::RelocationError::UndefinedSymbol(index)))?;
+                let s = match symbol.section_index {
+                    Symbol::INDEX_COMMON => object.commons[&index],
+                    _ => resolver.symbol(object, symbol, symbols.name(symbol).unwrap_or(""))?,
+                };
+                let a = rela.addend;
+                let p = target.wrapping_add(rela.offset);
+
+                // The field has to lie inside the section it patches
+                let in_section = rela
+                    .offset
+                    .checked_add(width(rela.relocation_type()))
+                    .map_or(false, |end| end <= target_size);
+                if !in_section {
+                    return Err(ModuleError::Relocation(elf::RelocationError::Overflow(p)));
+                }
+
+                match rela.relocation_type() {
+                    Rela::X86_64_NONE => (),
+                    Rela::X86_64_64 => unsafe {
+                        core::ptr::write_unaligned(p as *mut u64, s.wrapping_add(a as u64))
+                    },
+                    Rela::X86_64_PC64 => unsafe {
+                        core::ptr::write_unaligned(
+                            p as *mut u64,
+                            s.wrapping_add(a as u64).wrapping_sub(p),
+                        )
+                    },
+                    Rela::X86_64_PC32 | Rela::X86_64_PLT32 => {
+                        let mut value = (s as i64).wrapping_add(a).wrapping_sub(p as i64);
+                        // Calls into the kernel are too far for a rel32, go through a stub
+                        if value < i32::MIN as i64 || value > i32::MAX as i64 {
+                            let stub = resolver.stub(s);
+                            value = (stub as i64).wrapping_add(a).wrapping_sub(p as i64);
+                        }
+                        write_32(p, value, true)?;
+                    }
+                    Rela::X86_64_GOTPCREL
+                    | Rela::X86_64_GOTPCRELX
+                    | Rela::X86_64_REX_GOTPCRELX => {
+                        let slot = resolver.got(s);
+                        write_32(p, (slot as i64).wrapping_add(a).wrapping_sub(p as i64), true)?;
+                    }
+                    Rela::X86_64_32 => write_32(p, (s as i64).wrapping_add(a), false)?,
+                    Rela::X86_64_32S => write_32(p, (s as i64).wrapping_add(a), true)?,
+                    rtype => {
+                        return Err(ModuleError::Relocation(elf::RelocationError::Unsupported(
+                            rtype,
+                        )))
+                    }
+                }
+            }
+        }
+    }
+
+    /* Drop to the final permissions of each part */
+    let ends = [starts[1], starts[2], base + size];
+    for kind in [Kind::Text, Kind::ReadOnly, Kind::Data] {
+        let (start, end) = (starts[kind as usize], ends[kind as usize]);
+        let pages = Page::<Size4KiB>::range(
+            Page::containing_address(VirtAddr::new(start)),
+            Page::containing_address(VirtAddr::new(end)),
+        );
+        for page in pages {
+            unsafe {
+                mapper
+                    .update_flags(page, kind.flags())
+                    .expect("Unable to protect module page!")
+                    .ignore();
+            }
+        }
+    }
+    tlb::flush_all();
+
+    let entry = |name: &str| resolver.globals.get(name).map(|&(address, _)| address);
+    Ok(Linked {
+        base,
+        size,
+        init: entry(DRIVER_INIT),
+        uninit: entry(DRIVER_UNINIT),
+    })
+}
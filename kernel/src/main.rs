@@ -13,26 +13,32 @@
 extern crate alloc;
 
 mod acpi;
+mod coredump;
 mod drivers;
+mod exports;
 mod interrupts;
+mod linker;
+mod modules;
 mod process_manager;
+mod runtime;
+mod symbols;
 mod syscall;
 
 use core::arch::{asm, x86_64};
 use core::panic::PanicInfo;
 
 use boot_fs::BootImageFS;
-use common::memory_regions::PAGE_TABLE_OFFSET;
+use common::memory_map::RegionKind;
+use common::memory_regions;
 use common::serial::SerialPort;
 use macros::wchar;
 
 use common::x86_64::registers::control::{Cr3, Cr3Flags};
 use common::x86_64::structures::paging::{
-    FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size2MiB,
-    Size4KiB, Translate,
+    FrameAllocator, PageTable, PhysFrame, Size2MiB, Size4KiB, Translate,
 };
 use common::x86_64::{PhysAddr, VirtAddr};
-use common::{allocator, efi, elf, gdt, kprint, kprintln, mem, process, size_gb, KernelParameters};
+use common::{allocator, cpu, efi, elf, gdt, kprint, kprintln, mem, mmio, process, size_gb, KernelParameters};
 
 use crate::drivers::pci;
 use crate::process_manager::ManagedProcess;
@@ -43,6 +49,10 @@ use common::efi::{
 
 const BOOT_IMAGE: u64 = size_gb!(100);
 
+/// Set when the boot entry asks to wait for a debugger, `set var DEBUG_WAIT = 0` continues
+#[no_mangle]
+static mut DEBUG_WAIT: bool = false;
+
 #[no_mangle]
 pub extern "C" fn _start(parameters: &'static mut KernelParameters) -> ! {
     // kprintln!("Kernel... {:p}", parameters.system_table);
@@ -52,23 +62,54 @@ pub extern "C" fn _start(parameters: &'static mut KernelParameters) -> ! {
     fmt::write(&mut serial, format_args!("Kernel.. {:p}", parameters.system_table)).expect("Unable to print!");
     fmt::write(&mut serial, format_args!("\r\n")).expect("Unable to print!");
 
+    unsafe {
+        // Bases chosen by the loader, everything below depends on them
+        memory_regions::set_layout(parameters.layout);
+    }
+    kprintln!("Layout {:x?}", parameters.layout);
+
     allocator::init_heap(&parameters.heap);
 
+    // The loader's descriptor tables live in loader memory, which isn't mapped anymore
+    gdt::init();
+
     unsafe {
         // Set the static system table reference
         efi::register_global_system_table(parameters.system_table).unwrap();
     }
 
-    let wait = false;
-    while core::convert::identity(wait) {
-        unsafe { asm!("pause") }
+    kprintln!("Command line: {:?}", parameters.command_line);
+
+    unsafe {
+        DEBUG_WAIT = parameters.debug;
+        while core::ptr::read_volatile(&DEBUG_WAIT) {
+            asm!("pause")
+        }
     }
 
     // let frame_allocator = mem::PageTableFrameAllocator::new(parameters.memory_map);
-    let mut mapper = unsafe { mem::init(parameters.frame_allocator.clone(), PAGE_TABLE_OFFSET) };
-    mem::allocator().lock().swap_map(parameters.memory_map);
-
-    let mem_size = efi::get_mem_size(parameters.memory_map);
+    let mut mapper = unsafe { mem::init(parameters.frame_allocator.clone(), memory_regions::physmap_base()) };
+    allocator::init_slabs();
+    // heap_limit=<MiB> on the command line caps how far the heap grows
+    let heap_limit = parameters
+        .command_line
+        .split_whitespace()
+        .find_map(|arg| arg.strip_prefix("heap_limit="))
+        .and_then(|mb| mb.parse::<usize>().ok())
+        .map_or(memory_regions::HEAP_LIMIT, |mb| mb * 1024 * 1024);
+    allocator::set_heap_limit(heap_limit);
+    mmio::init_pat();
+    let protection = cpu::enable_protection();
+    kprintln!("Protection: {:?}", protection);
+    let memory_map = mem::allocator().lock().memory_map().clone();
+    memory_map.print();
+    let mem_size = memory_map.highest_address() as usize;
+
+    runtime::init();
+    match runtime::time() {
+        Ok(time) => kprintln!("Time: {:?}", time),
+        Err(e) => kprintln!("An error occured! {:x} GetTime", e),
+    }
     // unsafe {
     //     mem::KERNEL_MAP = table as u64;
     // }
@@ -76,54 +117,26 @@ pub extern "C" fn _start(parameters: &'static mut KernelParameters) -> ! {
     // efi::print_memory_map(parameters.memory_map);
     // allocator::init_heap_new(&mut mapper, &mut frame_allocator, parameters.heap_top, false).expect("Unable to create heap!");
 
-    // acpi::init(parameters.memory_map);
-    acpi::init(parameters.memory_map);
+    acpi::init(&memory_map);
 
     // Setup interrupts
     interrupts::init();
+    coredump::init();
 
     pci::init();
     acpi::aml::init();
+
     //pci::gather_devices();
 
     // interrupts::enable_apic();
-    mem::map_virt::<Size2MiB>(
-        PhysAddr::new(parameters.boot_image.0),
+    /* Mapped from the 2MiB boundary below the image so it can use 2MiB pages */
+    let pt = PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(parameters.boot_image.0));
+    mem::map_virt(
+        pt.start_address(),
         VirtAddr::new(BOOT_IMAGE),
-        parameters.boot_image.1 as usize,
+        (parameters.boot_image.0 + parameters.boot_image.1 - pt.start_address().as_u64()) as usize,
     )
-    .unwrap();
-
-    let pt = PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(parameters.boot_image.0));
-    let pt_end = PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(
-        parameters.boot_image.0 + parameters.boot_image.1,
-    ));
-
-    // let pt = PhysFrame::<Size2MiB>::containing_address(VirtAddr::new(BOOT_IMAGE));
-    // let pt_end = PhysFrame::<Size2MiB>::containing_address(VirtAddr::new(
-    //     parameters.boot_image.0 + parameters.boot_image.1,
-    // ));
-    // let pgs = Page::<Size2MiB>::range_inclusive(pt, pt_end);
-
-    for frame in PhysFrame::<Size2MiB>::range_inclusive(pt, pt_end) {
-        let page = Page::containing_address(VirtAddr::new(
-            (frame.start_address().as_u64() - pt.start_address().as_u64()) + BOOT_IMAGE,
-        ));
-        kprintln!(
-            "Mapping {:x} - {:x}",
-            frame.start_address().as_u64(),
-            page.start_address().as_u64()
-        );
-        unsafe {
-            <OffsetPageTable as Mapper<Size2MiB>>::map_to(
-                &mut mem::active_offset_page_table(PAGE_TABLE_OFFSET),
-                page,
-                frame,
-                PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
-                mem::allocator().get_mut(),
-            )
-        };
-    }
+    .expect("Unable to map boot image!");
 
     let ptr = BOOT_IMAGE + parameters.boot_image.0 - pt.start_address().as_u64();
     let ptr = ptr as *const u8;
@@ -133,8 +146,21 @@ pub extern "C" fn _start(parameters: &'static mut KernelParameters) -> ! {
     let file_data =
         unsafe { core::slice::from_raw_parts(ptr as *const u8, parameters.boot_image.1 as usize) };
 
+    mem::allocator()
+        .lock()
+        .charge(mem::Owner::BootImage, parameters.boot_image.1);
+
+    /* The parameters live on the loader stack in boot services memory, this was their last use.
+     * Nothing refers to ACPI tables or boot services memory anymore. */
+    let reclaimed = {
+        let mut allocator = mem::allocator().lock();
+        allocator.reclaim(RegionKind::BootServices) + allocator.reclaim(RegionKind::AcpiReclaim)
+    };
+    kprintln!("Reclaimed {} KiB", reclaimed / 1024);
+
     let image = BootImageFS::new(file_data);
     process::set_syscall_sp();
+    syscall::init();
 
     kprintln!("Boot Image: ");
     for file in image.files() {
@@ -144,19 +170,35 @@ pub extern "C" fn _start(parameters: &'static mut KernelParameters) -> ! {
     let mut files = image.files();
 
     let kernel = files.next().unwrap();
-    let kernel_exec_file = elf::ElfFile::new(image.file_data(kernel));
+    let kernel_exec_file =
+        elf::ElfFile::parse(image.file_data(kernel)).expect("Unable to parse kernel image!");
+    symbols::init(image.file_data(kernel));
 
     let driver = files.next().unwrap();
-    let driver_exec_file = elf::ElfFile::new(image.file_data(driver));
+    let driver_exec_file =
+        elf::ElfFile::parse(image.file_data(driver)).expect("Unable to parse driver image!");
     let ddate = driver_exec_file.data;
 
     let new_process =
         ManagedProcess::new_kernel_process(&driver_exec_file, &kernel_exec_file, 0, 0, mem_size);
+    if let Err(e) = &new_process {
+        kprintln!("Unable to load {}: {:?}", driver.name().trim_end_matches('\0'), e);
+    }
+
+    /* The rest of the boot image are driver modules */
+    for file in files {
+        let name = file.name().trim_end_matches('\0');
+        if let Err(e) = modules::load(name, image.file_data(file)) {
+            kprintln!("Unable to load {}: {:?}", name, e);
+        }
+    }
 
     // unsafe {
     //     processes::jump_usermode(&mapper, &new_process);
     // }
 
+    mem::print_usage();
+
     process_manager::init();
 
     common::x86_64::instructions::interrupts::enable();
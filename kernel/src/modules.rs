@@ -0,0 +1,252 @@
+use alloc::{string::String, vec::Vec};
+use common::{
+    elf::{self, ElfFile, FileType, SegmentType},
+    kprintln, mem,
+    memory_regions::{self, MODULE_SIZE, MODULE_START},
+    process, size_mb,
+    x86_64::{
+        structures::paging::{FrameDeallocator, Mapper, Page, Size4KiB},
+        VirtAddr,
+    },
+};
+
+use crate::{exports, linker};
+
+/// Symbols every driver exports, called once it is loaded and linked and before it is unloaded.
+/// `driver::driver!` generates them from the driver's `DriverCore` impl.
+pub const DRIVER_INIT: &str = "driver_init";
+pub const DRIVER_UNINIT: &str = "driver_uninit";
+
+pub struct Module {
+    pub name: String,
+    pub base: u64,
+    pub size: u64,
+    uninit: Option<extern "C" fn()>,
+}
+
+#[derive(Debug)]
+pub enum ModuleError {
+    Invalid(elf::ElfError),
+    NotSharedObject,
+    /// Modules share the kernel's FS base, there is nowhere to put a `PT_TLS` block
+    UnsupportedTls,
+    MissingLibrary(String),
+    UndefinedSymbol(String),
+    /// An archive without any relocatable objects
+    Empty,
+    OutOfSpace,
+    OutOfMemory,
+    Load(elf::LoadError),
+    Relocation(elf::RelocationError),
+}
+
+static MODULES: spin::Mutex<Vec<Module>> = spin::Mutex::new(Vec::new());
+
+/// Address ranges handed out in the module region
+struct Reservations {
+    /// Next free base address
+    next: u64,
+    /// Ranges below `next` given back by unloaded modules, as base and size
+    free: Vec<(u64, u64)>,
+}
+
+static RESERVATIONS: spin::Mutex<Reservations> = spin::Mutex::new(Reservations {
+    next: MODULE_START,
+    free: Vec::new(),
+});
+
+fn reserved_size(size: u64) -> u64 {
+    (size + size_mb!(2) - 1) & !(size_mb!(2) - 1)
+}
+
+/// Reserves `size` bytes of the module region, 2MiB aligned
+pub fn reserve(size: u64) -> Result<u64, ModuleError> {
+    if size > MODULE_SIZE {
+        return Err(ModuleError::OutOfSpace);
+    }
+    let size = reserved_size(size);
+    let mut reservations = RESERVATIONS.lock();
+    if let Some(index) = reservations.free.iter().position(|&(_, free)| free >= size) {
+        let (base, free) = reservations.free[index];
+        if free == size {
+            reservations.free.remove(index);
+        } else {
+            reservations.free[index] = (base + size, free - size);
+        }
+        return Ok(base);
+    }
+
+    if reservations.next + size > MODULE_START + MODULE_SIZE {
+        return Err(ModuleError::OutOfSpace);
+    }
+    let base = reservations.next;
+    reservations.next += size;
+    Ok(base)
+}
+
+/// Unmaps and frees whatever is mapped of the `size` bytes at `base`, a whole number of pages,
+/// and gives the reservation back
+pub fn release(base: u64, size: u64) {
+    let mut mapper = mem::active_offset_page_table(memory_regions::physmap_base());
+    let pages = Page::<Size4KiB>::range(
+        Page::containing_address(VirtAddr::new(base)),
+        Page::containing_address(VirtAddr::new(base + size)),
+    );
+    for page in pages {
+        if let Ok((frame, flush)) = mapper.unmap(page) {
+            flush.flush();
+            unsafe { mem::charged(mem::Owner::Module).deallocate_frame(frame) };
+        }
+    }
+
+    let reservations = &mut *RESERVATIONS.lock();
+    reservations.free.push((base, reserved_size(size)));
+    // Free ranges at the top go back to the bump allocator
+    while let Some(index) = reservations
+        .free
+        .iter()
+        .position(|&(base, size)| base + size == reservations.next)
+    {
+        reservations.next = reservations.free.remove(index).0;
+    }
+}
+
+fn entry(address: Option<u64>) -> Option<extern "C" fn()> {
+    address.map(|a| unsafe { core::mem::transmute(a as *const ()) })
+}
+
+/// Loads a driver from the boot image. Shared objects (`ET_DYN`) are mapped and relocated,
+/// relocatable objects (`ET_REL`) and `ar` archives of them are linked by `linker`. Imports are
+/// resolved against the kernel API, then the driver's `driver_init` runs.
+pub fn load(name: &str, data: &[u8]) -> Result<(), ModuleError> {
+    let (base, size, init, uninit) = if linker::is_archive(data) {
+        let linked = linker::link(linker::members(data))?;
+        (linked.base, linked.size, linked.init, linked.uninit)
+    } else {
+        let elf = ElfFile::parse(data).map_err(ModuleError::Invalid)?;
+        match elf.header().file_type() {
+            FileType::Relocatable => {
+                let linked = linker::link(core::iter::once((name, data)))?;
+                (linked.base, linked.size, linked.init, linked.uninit)
+            }
+            _ => load_shared(&elf)?,
+        }
+    };
+    kprintln!("Loaded {} at {:x}", name, base);
+
+    MODULES.lock().push(Module {
+        name: String::from(name),
+        base,
+        size,
+        uninit: entry(uninit),
+    });
+
+    match entry(init) {
+        Some(init) => init(),
+        None => kprintln!("{} has no {}", name, DRIVER_INIT),
+    }
+    Ok(())
+}
+
+/// Maps an `ET_DYN` driver into the module region and applies its dynamic relocations. Returns
+/// the base, size and entry points.
+fn load_shared(
+    elf: &ElfFile<'_>,
+) -> Result<(u64, u64, Option<u64>, Option<u64>), ModuleError> {
+    if !elf.header().is_position_independent() {
+        return Err(ModuleError::NotSharedObject);
+    }
+
+    if elf.tls().is_some() {
+        return Err(ModuleError::UnsupportedTls);
+    }
+
+    if let Some(library) = elf.needed().find(|&l| l != exports::KERNEL_LIBRARY) {
+        return Err(ModuleError::MissingLibrary(String::from(library)));
+    }
+
+    let loads = || {
+        elf.progam_headers()
+            .filter(|p| p.segment_type() == SegmentType::Load)
+    };
+    let start = loads().map(|p| p.virtual_address & !0xFFF).min().unwrap_or(0);
+    // Parsing checked the segment ends don't overflow
+    let end = loads()
+        .map(|p| p.virtual_address + p.segment_mem_size)
+        .max()
+        .unwrap_or(0);
+    let size = end
+        .checked_sub(start)
+        .and_then(|size| size.checked_add(0xFFF))
+        .ok_or(ModuleError::OutOfSpace)?
+        & !0xFFF;
+    // The slide only moves images up
+    if start > MODULE_START {
+        return Err(ModuleError::Load(elf::LoadError::BadAddress(start)));
+    }
+    let base = reserve(size)?;
+    let slide = base - start;
+
+    let mut mapper = mem::active_offset_page_table(memory_regions::physmap_base());
+    let mut current_mapper = mem::active_offset_page_table(memory_regions::physmap_base());
+    let mut last_page = None;
+    for pheader in loads() {
+        let loaded = process::load_segment(
+            elf,
+            pheader,
+            slide,
+            process::segment_flags(pheader),
+            last_page,
+            &mut mapper,
+            &mut current_mapper,
+            &mut mem::charged(mem::Owner::Module),
+        );
+        last_page = match loaded {
+            Ok(page) => page,
+            Err(e) => {
+                release(base, size);
+                return Err(ModuleError::Load(e));
+            }
+        };
+    }
+
+    let count = match process::relocate(elf, slide, &mapper, exports::lookup) {
+        Ok(count) => count,
+        Err(e) => {
+            release(base, size);
+            return Err(ModuleError::Relocation(e));
+        }
+    };
+    kprintln!("Applied {} relocations", count);
+
+    let entry = |name: &str| elf.lookup_dynamic(name).map(|s| s.value.wrapping_add(slide));
+    Ok((base, size, entry(DRIVER_INIT), entry(DRIVER_UNINIT)))
+}
+
+/// Runs a module's `driver_uninit` and unmaps it
+pub fn unload(name: &str) -> bool {
+    let module = {
+        let mut modules = MODULES.lock();
+        match modules.iter().position(|m| m.name == name) {
+            Some(index) => modules.remove(index),
+            None => return false,
+        }
+    };
+
+    if let Some(uninit) = module.uninit {
+        uninit();
+    }
+
+    release(module.base, module.size);
+    true
+}
+
+/// Module containing a kernel address, for fault messages. Gives up rather than spinning if the
+/// fault happened while the list was locked.
+pub fn containing(address: u64) -> Option<(String, u64)> {
+    MODULES
+        .try_lock()?
+        .iter()
+        .find(|m| address >= m.base && address < m.base + m.size)
+        .map(|m| (m.name.clone(), address - m.base))
+}
@@ -1,14 +1,23 @@
 use core::arch::asm;
 
-use crate::interrupts;
+use crate::{interrupts, syscall};
 use alloc::{collections::LinkedList, vec::Vec};
 use bitflags::bitflags;
 use common::{
     elf, kprintln,
-    process::Process,
+    process::{self, Process},
+    slab::{ObjectCache, SlabBox},
+    vma::VmError,
     x86_64::{
-        registers::control::{Cr3, Cr3Flags},
-        structures::paging::{Mapper, OffsetPageTable, PageTable, PhysFrame, Size4KiB, Translate},
+        instructions::segmentation::{Segment, CS, SS},
+        registers::{
+            control::{Cr3, Cr3Flags},
+            model_specific::FsBase,
+        },
+        structures::{
+            idt::{InterruptStackFrame, PageFaultErrorCode},
+            paging::{Mapper, OffsetPageTable, PageTable, PhysFrame, Size4KiB, Translate},
+        },
         VirtAddr,
     },
 };
@@ -19,8 +28,10 @@ pub enum State {
     Running,
 }
 
-static mut PROCESSES: Vec<ManagedProcess> = Vec::new();
+static PROCESS_CACHE: ObjectCache<ManagedProcess> = ObjectCache::new("process");
+static mut PROCESSES: Vec<SlabBox<ManagedProcess>> = Vec::new();
 static mut NEXT_PROCESS: usize = 0;
+static mut CURRENT: Option<process::ProcessId> = None;
 
 bitflags! {
     struct ProcessFlags: u32 {
@@ -41,10 +52,10 @@ impl ManagedProcess {
         kernel_stack_start: u64,
         kernel_stack_end: u64,
         mem_size: usize,
-    ) -> ManagedProcess {
+    ) -> Result<ManagedProcess, elf::LoadError> {
         let mut current_mapper =
-            common::mem::active_offset_page_table(common::memory_regions::PAGE_TABLE_OFFSET);
-        ManagedProcess {
+            common::mem::active_offset_page_table(common::memory_regions::physmap_base());
+        Ok(ManagedProcess {
             process: Process::from_elf(
                 elf,
                 kernel,
@@ -52,16 +63,20 @@ impl ManagedProcess {
                 kernel_stack_end,
                 mem_size,
                 &mut current_mapper,
-                common::mem::allocator().get_mut(),
-            ),
+                &mut common::mem::charged(common::mem::Owner::Process(process::next_id())),
+            )?,
             state: State::Ready,
             flags: ProcessFlags::KERNEL,
-        }
+        })
     }
 
     pub fn spawn(self) {
+        let process = match PROCESS_CACHE.alloc(self) {
+            Ok(process) => process,
+            Err(_) => panic!("Unable to allocate process!"),
+        };
         unsafe {
-            PROCESSES.push(self);
+            PROCESSES.push(process);
         }
     }
 
@@ -69,7 +84,7 @@ impl ManagedProcess {
         let ptr: *const PageTable = self.process.address_space.as_ref();
 
         let frame = match <OffsetPageTable as Translate>::translate_addr(
-            &mut crate::mem::active_offset_page_table(common::memory_regions::PAGE_TABLE_OFFSET),
+            &mut crate::mem::active_offset_page_table(common::memory_regions::physmap_base()),
             VirtAddr::new(ptr as u64),
         ) {
             Some(addr) => match PhysFrame::<Size4KiB>::from_start_address(addr) {
@@ -82,6 +97,112 @@ impl ManagedProcess {
         unsafe {
             Cr3::write(frame, Cr3Flags::empty());
         }
+        FsBase::write(VirtAddr::new(self.process.fs_base));
+        set_current(self.process.id);
+    }
+}
+
+pub fn set_current(id: process::ProcessId) {
+    unsafe {
+        CURRENT = Some(id);
+    }
+}
+
+fn current_managed() -> Option<&'static mut ManagedProcess> {
+    unsafe {
+        let id = CURRENT?;
+        PROCESSES
+            .iter_mut()
+            .find(|p| p.process.id == id)
+            .map(|p| &mut **p)
+    }
+}
+
+/// The process running in user mode, for syscalls
+pub fn current() -> Option<&'static mut Process> {
+    current_managed().map(|p| &mut p.process)
+}
+
+/// Whether the current process was loaded as part of the kernel, only those may manage modules
+pub fn current_is_kernel() -> bool {
+    current_managed().map_or(false, |p| p.flags.contains(ProcessFlags::KERNEL))
+}
+
+/// Spawns a copy of the current process and returns its id. The scheduler doesn't save user
+/// registers yet, so the child starts at the entry point with the parent's memory, like a process
+/// spawned from a template.
+pub fn fork_current() -> Result<process::ProcessId, VmError> {
+    let parent = current_managed().ok_or(VmError::InvalidArgument)?;
+    let child = ManagedProcess {
+        process: parent.process.fork()?,
+        state: State::Ready,
+        flags: parent.flags,
+    };
+    let id = child.process.id;
+    child.spawn();
+    Ok(id)
+}
+
+/// Resolves a user mode page fault of the current process. Runs in the kernel address space
+/// because the process doesn't map the kernel heap.
+pub fn handle_fault(address: u64, error_code: PageFaultErrorCode) -> bool {
+    let (process_cr3, flags) = Cr3::read();
+    unsafe { Cr3::write(syscall::kernel_address_space(), flags) };
+
+    let handled = current().map_or(false, |process| {
+        process.handle_fault(
+            address,
+            error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE),
+            error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH),
+        )
+    });
+
+    unsafe { Cr3::write(process_cr3, flags) };
+    handled
+}
+
+/// Removes the current process after a fault it can't recover from. The fault handler then
+/// returns to `idle` on the kernel stack rather than idling inside the exception.
+pub fn kill_current(stack_frame: &mut InterruptStackFrame) {
+    if let Some(id) = remove_current() {
+        kprintln!("Killed process {}", id);
+    }
+    unsafe {
+        let stack = (process::SYSCALL_SP & !0xF) - 8;
+        stack_frame.as_mut().update(|frame| {
+            frame.instruction_pointer = VirtAddr::new(idle as u64);
+            frame.code_segment = CS::get_reg().0 as u64;
+            frame.stack_segment = SS::get_reg().0 as u64;
+            frame.stack_pointer = VirtAddr::new(stack);
+            frame.cpu_flags = 0x202;
+        });
+    }
+}
+
+/// Removes the current process when it exits on its own
+pub fn exit_current(code: u64) -> ! {
+    if let Some(id) = remove_current() {
+        kprintln!("Process {} exited with {}", id, code);
+    }
+    idle()
+}
+
+/// Drops the current process, which frees its address space. That can't happen while it is
+/// active, so this switches to the kernel address space first.
+fn remove_current() -> Option<process::ProcessId> {
+    unsafe {
+        Cr3::write(syscall::kernel_address_space(), Cr3Flags::empty());
+        let id = CURRENT.take()?;
+        PROCESSES.retain(|p| p.process.id != id);
+        Some(id)
+    }
+}
+
+/// There is no other process to switch to until the scheduler works, so the CPU idles in the
+/// kernel address space
+fn idle() -> ! {
+    loop {
+        common::x86_64::instructions::interrupts::enable_and_hlt();
     }
 }
 
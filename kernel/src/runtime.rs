@@ -0,0 +1,154 @@
+use core::arch::asm;
+
+use alloc::{string::String, vec, vec::Vec};
+use common::{
+    efi::{self, guid::GUID, Char16, ResetType, Time},
+    kprintln, size_kb,
+    x86_64::{instructions::interrupts, registers::control::Cr3, structures::paging::PhysFrame},
+};
+use spin::Mutex;
+
+/* Firmware code can use a lot more stack than whatever we happen to be running on */
+const STACK_SIZE: usize = size_kb!(128);
+
+#[repr(C, align(16))]
+struct Stack([u8; STACK_SIZE]);
+
+static mut STACK: Stack = Stack([0; STACK_SIZE]);
+
+/// Kernel address space, the loader only mapped the runtime regions in there. Holding the lock
+/// serializes calls since the firmware isn't reentrant.
+static RUNTIME: Mutex<Option<PhysFrame>> = Mutex::new(None);
+
+/// Has to be called from the kernel address space
+pub fn init() {
+    let (frame, _) = Cr3::read();
+    *RUNTIME.lock() = Some(frame);
+}
+
+/// Runs `f` on the runtime services stack inside the kernel address space, with interrupts
+/// disabled and the runtime lock held
+fn call<R>(f: impl FnOnce(&efi::RuntimeServices) -> R) -> R {
+    let runtime = RUNTIME.lock();
+    let kernel_space = runtime.expect("EFI runtime services aren't initialized!");
+
+    interrupts::without_interrupts(|| {
+        let (current, flags) = Cr3::read();
+        if current != kernel_space {
+            unsafe { Cr3::write(kernel_space, flags) };
+        }
+
+        let services = efi::get_system_table().runtime_services();
+        let mut f = Some(f);
+        let mut result = None;
+        let mut thunk = || result = Some((f.take().unwrap())(services));
+
+        unsafe {
+            let stack_top = STACK.0.as_ptr() as u64 + STACK_SIZE as u64;
+            call_on_stack(stack_top, &mut thunk);
+        }
+
+        if current != kernel_space {
+            unsafe { Cr3::write(current, flags) };
+        }
+        result.unwrap()
+    })
+}
+
+unsafe fn call_on_stack(stack_top: u64, f: &mut dyn FnMut()) {
+    extern "sysv64" fn trampoline(f: *mut &mut dyn FnMut()) {
+        unsafe { (*f)() }
+    }
+
+    let mut f = f;
+    asm!(
+        "mov r12, rsp",
+        "mov rsp, {stack}",
+        "call {trampoline}",
+        "mov rsp, r12",
+        stack = in(reg) stack_top,
+        trampoline = in(reg) trampoline as usize,
+        in("rdi") &mut f as *mut &mut dyn FnMut(),
+        out("r12") _,
+        clobber_abi("sysv64"),
+    );
+}
+
+fn to_char16(string: &str) -> Vec<Char16> {
+    string.encode_utf16().chain(core::iter::once(0)).collect()
+}
+
+/// Wall clock time from the RTC
+pub fn time() -> Result<Time, usize> {
+    call(|rt| {
+        let mut time = Time::default();
+        match unsafe { rt.get_time(&mut time) } {
+            0 => Ok(time),
+            e => Err(e),
+        }
+    })
+}
+
+pub fn set_time(time: &Time) -> Result<(), usize> {
+    match call(|rt| unsafe { rt.set_time(time) }) {
+        0 => Ok(()),
+        e => Err(e),
+    }
+}
+
+/// Attributes and contents of a firmware variable
+pub fn variable(name: &str, vendor: &GUID) -> Result<(u32, Vec<u8>), usize> {
+    let name = to_char16(name);
+    let mut buffer = vec![0u8; 64];
+    loop {
+        let mut attributes = 0;
+        let mut size = 0;
+        let res = call(|rt| unsafe {
+            rt.get_variable(&name, vendor, &mut attributes, &mut size, &mut buffer)
+        });
+        match res {
+            0 => {
+                buffer.truncate(size);
+                return Ok((attributes, buffer));
+            }
+            efi::BUFFER_TOO_SMALL => buffer.resize(size, 0),
+            e => return Err(e),
+        }
+    }
+}
+
+/// Creates or updates a variable, empty `data` deletes it
+pub fn set_variable(name: &str, vendor: &GUID, attributes: u32, data: &[u8]) -> Result<(), usize> {
+    let name = to_char16(name);
+    match call(|rt| unsafe { rt.set_variable(&name, vendor, attributes, data) }) {
+        0 => Ok(()),
+        e => Err(e),
+    }
+}
+
+/// Names and vendors of every variable visible at runtime
+pub fn variable_names() -> Vec<(String, GUID)> {
+    let mut names = Vec::new();
+    let mut name = vec![0 as Char16; 256];
+    let mut vendor = efi::guid::GLOBAL_VARIABLE;
+    loop {
+        let res = call(|rt| unsafe { rt.get_next_variable_name(&mut name, &mut vendor) });
+        if res != 0 {
+            if res != efi::NOT_FOUND {
+                kprintln!("An error occured! {:x} GetNextVariableName", res);
+            }
+            break;
+        }
+
+        let len = name.iter().position(|c| *c == 0).unwrap_or(name.len());
+        names.push((String::from_utf16_lossy(&name[..len]), vendor));
+    }
+    names
+}
+
+/// Reboots or powers off through the firmware
+pub fn reset(reset_type: ResetType) -> ! {
+    kprintln!("Firmware reset {:?}", reset_type);
+    call(|rt| unsafe { rt.reset_system(reset_type, 0) });
+    unreachable!("ResetSystem returned!")
+}
@@ -0,0 +1,34 @@
+use common::{elf::ElfFile, memory_regions};
+
+/// The kernel's own ELF from the boot image, it stays mapped for the lifetime of the kernel
+static mut KERNEL_IMAGE: Option<ElfFile<'static>> = None;
+
+pub fn init(data: &[u8]) {
+    // The boot image mapping is never torn down
+    let data = unsafe { core::slice::from_raw_parts(data.as_ptr(), data.len()) };
+    unsafe {
+        KERNEL_IMAGE = ElfFile::parse(data).ok();
+    }
+}
+
+/// Kernel function containing `address` and the offset into it
+pub fn lookup(address: u64) -> Option<(&'static str, u64)> {
+    let image = unsafe { KERNEL_IMAGE.as_ref()? };
+    let slide = memory_regions::layout().kernel_slide;
+    image.resolve(address.checked_sub(slide)?)
+}
+
+/// Formats an address as `symbol+offset` when it can be resolved
+pub struct Symbolized(pub u64);
+
+impl core::fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if let Some((name, offset)) = lookup(self.0) {
+            return write!(f, "{:x} <{}+{:#x}>", self.0, name, offset);
+        }
+        match crate::modules::containing(self.0) {
+            Some((module, offset)) => write!(f, "{:x} <{}+{:#x}>", self.0, module, offset),
+            None => write!(f, "{:x}", self.0),
+        }
+    }
+}
@@ -1,10 +1,26 @@
-use core::{arch::asm, marker::PhantomData};
-use common::{process::{self, Process}, x86_64::{structures::paging::{OffsetPageTable, PageTable, PhysFrame, Size4KiB, Translate}, VirtAddr, registers::control::{Cr3, Cr3Flags}}};
+use core::{
+    arch::{asm, global_asm},
+    marker::PhantomData,
+};
+use common::{process::Process, vma::{Protection, VmError}, x86_64::{structures::paging::{OffsetPageTable, PageTable, PhysFrame, Size4KiB, Translate}, PhysAddr, VirtAddr, registers::{control::{Cr3, Cr3Flags}, model_specific::FsBase}}};
 
-use crate::{interrupt_begin, interrupt_end, interrupts::CpuSnapshot};
+use common::kprintln;
+
+use crate::{interrupts::CpuSnapshot, modules, process_manager};
+
+/// Address space syscalls run in, the process address space doesn't map the kernel heap
+#[no_mangle]
+static mut KERNEL_CR3: u64 = 0;
 
 enum SyscallType {
     Write,
+    Mmap,
+    Munmap,
+    Mprotect,
+    Fork,
+    Exit,
+    DumpHeap,
+    UnloadModule,
     Unknown,
 }
 
@@ -12,6 +28,13 @@ impl From<u64> for SyscallType {
     fn from(a: u64) -> Self {
         match a {
             0 => SyscallType::Write,
+            1 => SyscallType::Mmap,
+            2 => SyscallType::Munmap,
+            3 => SyscallType::Mprotect,
+            4 => SyscallType::Fork,
+            5 => SyscallType::Exit,
+            6 => SyscallType::DumpHeap,
+            7 => SyscallType::UnloadModule,
             _ => SyscallType::Unknown,
         }
     }
@@ -81,44 +104,158 @@ impl<'a> Syscall<'a, Four> {
     }
 }
 
-// #[naked]
-unsafe extern "C" fn syscall_entry_stub() {
-    interrupt_begin!();
+/// Must run in the kernel address space
+pub fn init() {
+    unsafe {
+        KERNEL_CR3 = Cr3::read().0.start_address().as_u64();
+    }
+}
+
+pub fn kernel_address_space() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(unsafe { KERNEL_CR3 }))
+}
+
+/* SYSCALL leaves rsp on the user stack, which SMAP doesn't let the kernel touch. The stub
+ * switches to the kernel address space and stack before it saves anything, the snapshot ends up
+ * on the kernel stack. */
+global_asm!(
+    "
+    .global syscall_entry_stub
+syscall_entry_stub:
+    mov qword ptr [rip + SYSCALL_USP], rsp
+    mov rsp, cr3
+    mov qword ptr [rip + SYSCALL_UMAP], rsp
+    mov rsp, qword ptr [rip + KERNEL_CR3]
+    mov cr3, rsp
+    mov rsp, qword ptr [rip + SYSCALL_SP]
+
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    push rbp
 
-    let mut cpu: *const CpuSnapshot = core::ptr::null_mut();
+    mov rdi, rsp
+    mov rbx, rsp
+    and rsp, -16
+    call syscall_entry
+    mov rsp, rbx
 
-    asm!("mov rdx, rsp", options(nomem, nostack)); // Save old stack
-    asm!("mov rsp, rax; mov rbp, rsp", in("rax") process::SYSCALL_SP, options(nostack)); // Load kernel stack
-    asm!("", out("rdx") process::SYSCALL_USP, options(nostack)); // Save old stack into variable for later
-    asm!("", out("rdx") cpu, options(nostack)); // Load address into pointer for cpu snapshot
+    pop rbp
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
 
-    asm!("mov rax, cr3", out("rax") process::SYSCALL_UMAP, options(nostack)); // Save old address space
+    mov rsp, qword ptr [rip + SYSCALL_UMAP]
+    mov cr3, rsp
+    mov rsp, qword ptr [rip + SYSCALL_USP]
+    sysretq
+    "
+);
+
+extern "C" {
+    fn syscall_entry_stub();
+}
 
-    syscall_entry(&*cpu);
+/// The syscall number is in rdi, arguments in r8, r9, r10, r12 and r13. The result goes back in
+/// rax, negative errno values are errors.
+#[no_mangle]
+extern "C" fn syscall_entry(cpu: &mut CpuSnapshot) {
+    let result = match SyscallType::from(cpu.rdi) {
+        SyscallType::Mmap => memory_syscall(|process| {
+            let protection = Protection::from_bits(cpu.r10).ok_or(VmError::InvalidArgument)?;
+            process.mmap(cpu.r8, cpu.r9, protection, cpu.r12, cpu.r13)
+        }),
+        SyscallType::Munmap => memory_syscall(|process| process.munmap(cpu.r8, cpu.r9).map(|_| 0)),
+        SyscallType::Mprotect => memory_syscall(|process| {
+            let protection = Protection::from_bits(cpu.r10).ok_or(VmError::InvalidArgument)?;
+            process.mprotect(cpu.r8, cpu.r9, protection).map(|_| 0)
+        }),
+        SyscallType::Fork => memory_syscall(|_| process_manager::fork_current().map(u64::from)),
+        SyscallType::Exit => process_manager::exit_current(cpu.r8),
+        SyscallType::DumpHeap => {
+            dump_heap();
+            0
+        }
+        SyscallType::UnloadModule => memory_syscall(|process| unload_module(process, cpu.r8, cpu.r9)),
+        SyscallType::Write | SyscallType::Unknown => return,
+    };
+    cpu.rax = result;
+}
 
-    asm!("mov cr3, rax", in("rax") process::SYSCALL_UMAP, options(nostack));
-    asm!("mov rsp, rax", in("rax") process::SYSCALL_USP, options(nostack));
+/// Prints every live kernel allocation with the return addresses it was made from, to trace leaks
+#[cfg(feature = "debug_heap")]
+fn dump_heap() {
+    use common::debug_heap;
 
-    interrupt_end!();
+    let (count, bytes) = debug_heap::live();
+    kprintln!("{} live allocations, {} bytes", count, bytes);
+    // Nothing in here may allocate, the live list is locked
+    debug_heap::for_each_live(|block| {
+        kprintln!("  {:#x}: {} bytes", block.address, block.size);
+        for &caller in block.callers.iter().filter(|&&caller| caller != 0) {
+            match crate::symbols::lookup(caller) {
+                Some((name, offset)) => kprintln!("    {:x} <{}+{:#x}>", caller, name, offset),
+                None => kprintln!("    {:x}", caller),
+            }
+        }
+    });
+}
 
-    asm!("sysretq", options(noreturn));
+#[cfg(not(feature = "debug_heap"))]
+fn dump_heap() {
+    kprintln!("The kernel is built without debug_heap, allocations aren't tracked");
 }
 
-fn syscall_entry(cpu: &CpuSnapshot) {
-    // kprint!(".")
-    // let syscall_type = cpu.rdi;
-    // let syscall_type = SyscallType::from(syscall_type);
+/// Unloads the module named by the `size` bytes at `address`. Only kernel processes may, the
+/// module's `driver_uninit` runs in ring 0.
+fn unload_module(process: &mut Process, address: u64, size: u64) -> Result<u64, VmError> {
+    if !process_manager::current_is_kernel() {
+        return Err(VmError::Forbidden);
+    }
+    let mut name = [0u8; 64];
+    let name = name
+        .get_mut(..size as usize)
+        .ok_or(VmError::InvalidArgument)?;
+    process.copy_from_user(address, name)?;
+    let name = core::str::from_utf8(name).map_err(|_| VmError::InvalidArgument)?;
+    if modules::unload(name) {
+        Ok(0)
+    } else {
+        Err(VmError::InvalidArgument)
+    }
+}
 
-    // match syscall_type {
-    //     SyscallType::Write => {
-    //         let string = unsafe {
-    //             core::str::from_utf8(core::slice::from_raw_parts(cpu.r8 as *const u8, cpu.r9 as usize))
-    //         }
-    //         .unwrap();
-    //         kprint!("{}", string);
-    //     }
-    //     SyscallType::Unknown => (),
-    // }
+fn memory_syscall(f: impl FnOnce(&mut Process) -> Result<u64, VmError>) -> u64 {
+    let result = match process_manager::current() {
+        Some(process) => f(process),
+        None => Err(VmError::InvalidArgument),
+    };
+    match result {
+        Ok(value) => value,
+        Err(e) => e.errno() as u64,
+    }
 }
 
 #[inline(never)]
@@ -137,6 +274,7 @@ pub unsafe fn jump_usermode(mapper: &OffsetPageTable, process: &Process) {
         None => panic!("Unable to get frame! (2)"),
     };
 
+    /* SFMASK clears IF and AC on syscall entry, AC left set by user mode would turn SMAP off */
     asm!(
         "
     mov rcx, 0xc0000082 
@@ -149,7 +287,7 @@ pub unsafe fn jump_usermode(mapper: &OffsetPageTable, process: &Process) {
     rdmsr               
     mov edx, 0x00180008
     wrmsr           	
-    mov eax, 0x200
+    mov eax, 0x40200
     mov rcx, 0xc0000084
     wrmsr
     ",
@@ -159,7 +297,9 @@ pub unsafe fn jump_usermode(mapper: &OffsetPageTable, process: &Process) {
     in("r12") process.stack_base
     );
 
+    process_manager::set_current(process.id);
     Cr3::write(frame, Cr3Flags::empty());
+    FsBase::write(VirtAddr::new(process.fs_base));
 
     asm!(
         "
@@ -1,20 +1,166 @@
 // use linked_list_allocator::LockedHeap;
 
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
 use crate::{
-    linked_list_allocator::{Heap, LockedHeap},
-    memory_regions::{HEAP_SIZE, HEAP_START},
+    linked_list_allocator::{self, Heap, LockedHeap},
+    mem::{self, Owner},
+    memory_regions::{self, HEAP_RESERVE, HEAP_SIZE},
+    slab,
 };
 
+#[cfg(feature = "debug_heap")]
+use crate::debug_heap;
+
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, page::PageRangeInclusive, FrameAllocator, Mapper, Page, PageTableFlags,
-        Size4KiB,
+        mapper::MapToError, page::PageRangeInclusive, FrameAllocator, FrameDeallocator, Mapper,
+        Page, PageSize, PageTableFlags, Size4KiB,
     },
     VirtAddr,
 };
 
+/// Small allocations go to the slab caches once they are enabled, everything else and anything
+/// allocated before to the heap. Frees are told apart by address.
+struct KernelAllocator {
+    heap: LockedHeap,
+    slabs: AtomicBool,
+    /// Size the heap may grow to, 0 until the kernel lets it grow
+    limit: AtomicUsize,
+}
+
+/* The heap grows by at least this much at a time */
+const HEAP_GROWTH: usize = size_mb!(1);
+
+impl KernelAllocator {
+    unsafe fn allocate(&self, layout: Layout) -> *mut u8 {
+        if self.slabs.load(Ordering::Relaxed) {
+            if let Some(ptr) = slab::allocate(layout) {
+                return ptr;
+            }
+        }
+        let mut heap = self.heap.lock();
+        if let Ok(ptr) = heap.allocate_first_fit(layout) {
+            return ptr.as_ptr();
+        }
+        if grow(&mut heap, layout, self.limit.load(Ordering::Relaxed)) {
+            if let Ok(ptr) = heap.allocate_first_fit(layout) {
+                return ptr.as_ptr();
+            }
+        }
+        core::ptr::null_mut()
+    }
+
+    /// Bounds of the heap, anything outside of them comes from the slabs
+    fn heap_bounds(&self) -> (usize, usize) {
+        let heap = self.heap.lock();
+        (heap.bottom(), heap.top())
+    }
+
+    unsafe fn free(&self, ptr: *mut u8, layout: Layout) {
+        let (bottom, top) = self.heap_bounds();
+        if (bottom..top).contains(&(ptr as usize)) {
+            self.heap.dealloc(ptr, layout)
+        } else {
+            slab::deallocate(ptr, layout)
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    #[cfg(not(feature = "debug_heap"))]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.allocate(layout)
+    }
+
+    #[cfg(not(feature = "debug_heap"))]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.free(ptr, layout)
+    }
+
+    /* The debug heap starts tracking along with the slabs, the loader's blocks have no header */
+    #[cfg(feature = "debug_heap")]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if !self.slabs.load(Ordering::Relaxed) {
+            return self.allocate(layout);
+        }
+        let block = self.allocate(debug_heap::block_layout(layout));
+        if block.is_null() {
+            return block;
+        }
+        debug_heap::init(block, layout)
+    }
+
+    #[cfg(feature = "debug_heap")]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (bottom, top) = self.heap_bounds();
+        let lowest = if (bottom..top).contains(&(ptr as usize)) { bottom } else { 0 };
+        if debug_heap::tracked(ptr, layout, lowest) {
+            let block = debug_heap::release(ptr, layout);
+            self.free(block, debug_heap::block_layout(layout))
+        } else {
+            self.free(ptr, layout)
+        }
+    }
+}
+
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: KernelAllocator = KernelAllocator {
+    heap: LockedHeap::empty(),
+    slabs: AtomicBool::new(false),
+    limit: AtomicUsize::new(0),
+};
+
+/// Maps frames past the top of the heap until `layout` fits, as long as the heap stays within
+/// `limit`. The frames are mapped in the kernel's address space whichever one is active, process
+/// address spaces don't map the heap. Returns whether the heap grew at all.
+fn grow(heap: &mut Heap, layout: Layout, limit: usize) -> bool {
+    let by = linked_list_allocator::align_up(layout.size() + layout.align(), HEAP_GROWTH);
+    if heap.size() + by > limit.min(HEAP_RESERVE) {
+        if limit != 0 {
+            kprintln!("Heap limit of {} KiB reached", limit / 1024);
+        }
+        return false;
+    }
+
+    let mut mapper = mem::kernel_page_table();
+    let mut allocator = mem::charged(Owner::Heap);
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    if mem::nx_enabled() {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+
+    let top = VirtAddr::new(heap.top() as u64);
+    let mut mapped = 0;
+    for page in Page::<Size4KiB>::range(
+        Page::containing_address(top),
+        Page::containing_address(top + by),
+    ) {
+        let frame = match allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => break,
+        };
+        match unsafe { mapper.map_to(page, frame, flags, &mut allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(_) => {
+                unsafe { allocator.deallocate_frame(frame) };
+                break;
+            }
+        }
+        mapped += Size4KiB::SIZE as usize;
+    }
+
+    if mapped == 0 {
+        return false;
+    }
+    // Whatever got mapped is handed to the heap, even if it isn't enough
+    unsafe { heap.extend(mapped) };
+    kprintln!("Heap grew by {} KiB to {} KiB", mapped / 1024, heap.size() / 1024);
+    true
+}
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
@@ -23,12 +169,12 @@ fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
 
 pub fn init_heap(heap: &Heap) {
     unsafe {
-        ALLOCATOR.lock().update(heap);
+        ALLOCATOR.heap.lock().update(heap);
     }
 }
 
 pub fn heap_range(offset: usize) -> PageRangeInclusive {
-    let heap_start = VirtAddr::new((HEAP_START + offset) as u64);
+    let heap_start = VirtAddr::new((memory_regions::heap_start() + offset) as u64);
     let heap_end = heap_start + (HEAP_SIZE + offset) - 1u64;
     let heap_start_page = Page::<Size4KiB>::containing_address(heap_start);
     let heap_end_page = Page::containing_address(heap_end);
@@ -58,16 +204,28 @@ pub fn init_heap_new(
     }
 
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START + offset, HEAP_SIZE);
+        ALLOCATOR.heap.lock().init(memory_regions::heap_start() + offset, HEAP_SIZE);
     }
 
     Ok(())
 }
 
 pub fn heap_top() -> usize {
-    unsafe { ALLOCATOR.lock().top() }
+    ALLOCATOR.heap.lock().top()
 }
 
 pub fn heap() -> Heap {
-    unsafe { ALLOCATOR.heap() }
+    ALLOCATOR.heap.heap()
+}
+
+/// Serves small allocations from the slab caches from now on, and starts tracking them with the
+/// debug heap. Slab pages are reached through the physical memory mapping, so this waits until
+/// the kernel has set it up with `mem::init`.
+pub fn init_slabs() {
+    ALLOCATOR.slabs.store(true, Ordering::SeqCst);
+}
+
+/// Lets the heap grow on demand up to `limit` bytes, capped by the reserved range
+pub fn set_heap_limit(limit: usize) {
+    ALLOCATOR.limit.store(limit, Ordering::SeqCst);
 }
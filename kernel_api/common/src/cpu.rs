@@ -0,0 +1,81 @@
+use core::{
+    arch::{
+        asm,
+        x86_64::{__cpuid, __cpuid_count},
+    },
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+
+use crate::mem;
+
+/// Set once SMAP is on, `clac` is an undefined instruction without it. The kernel's
+/// fault stubs check it by name.
+#[no_mangle]
+static SMAP: AtomicBool = AtomicBool::new(false);
+
+/// Protection features of the processor, from CPUID
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Features {
+    pub nx: bool,
+    pub smep: bool,
+    pub smap: bool,
+    pub umip: bool,
+}
+
+impl Features {
+    pub fn detect() -> Features {
+        let max_leaf = unsafe { __cpuid(0) }.eax;
+        let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+
+        let mut features = Features::default();
+        if max_extended_leaf >= 0x8000_0001 {
+            features.nx = unsafe { __cpuid(0x8000_0001) }.edx & (1 << 20) != 0;
+        }
+        if max_leaf >= 7 {
+            let structured = unsafe { __cpuid_count(7, 0) };
+            features.smep = structured.ebx & (1 << 7) != 0;
+            features.smap = structured.ebx & (1 << 20) != 0;
+            features.umip = structured.ecx & (1 << 2) != 0;
+        }
+        features
+    }
+}
+
+/// Turns on every protection the processor has: NX pages, SMEP and SMAP so the kernel can't
+/// execute or touch user pages by accident, UMIP so user mode can't read descriptor table
+/// addresses, and CR0.WP so read-only pages are read-only for the kernel too. Kernel mappings
+/// have to be supervisor-only before this runs. Returns what got enabled.
+pub fn enable_protection() -> Features {
+    let mut features = Features::detect();
+    features.nx = mem::enable_nx();
+
+    let mut flags = Cr4Flags::empty();
+    if features.smep {
+        flags |= Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION;
+    }
+    if features.smap {
+        flags |= Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION;
+    }
+    if features.umip {
+        flags |= Cr4Flags::USER_MODE_INSTRUCTION_PREVENTION;
+    }
+
+    unsafe {
+        Cr0::update(|cr0| cr0.insert(Cr0Flags::WRITE_PROTECT));
+        Cr4::update(|cr4| cr4.insert(flags));
+    }
+    SMAP.store(features.smap, Ordering::SeqCst);
+    features
+}
+
+/// Forbids supervisor accesses to user pages again. The kernel never sets AC itself, it reaches
+/// user memory only through the physmap (`Process::copy_from_user`), so entry paths just make
+/// sure user mode didn't leave it set.
+#[inline(always)]
+pub fn clac() {
+    if SMAP.load(Ordering::Relaxed) {
+        unsafe { asm!("clac", options(nostack)) };
+    }
+}
@@ -0,0 +1,197 @@
+use core::{alloc::Layout, arch::asm, ptr};
+
+use spinning_top::Spinlock;
+
+/* Every block is laid out as header, front redzone, the allocation and the back redzone */
+const REDZONE: usize = 16;
+const CANARY: u8 = 0xFD;
+/// Freed memory is filled with this, reads of freed memory show up as 0x6B6B...
+const POISON: u8 = 0x6B;
+/// New allocations are filled with this so uninitialized reads stand out
+const UNINITIALIZED: u8 = 0xCD;
+
+const LIVE: u64 = 0x4C49_5645_424C_4F4B;
+const FREED: u64 = 0x4652_4545_424C_4F4B;
+
+/// Return addresses recorded per allocation, the first ones are inside the allocator
+pub const CALLERS: usize = 6;
+/* Frames further apart than this end the walk, the frame pointer is garbage by then */
+const FRAME_LIMIT: u64 = size_kb!(64);
+
+#[repr(C)]
+struct Header {
+    /* The heap and the slabs reuse the start of a freed block, so the magic comes last */
+    prev: *mut Header,
+    next: *mut Header,
+    size: usize,
+    align: usize,
+    callers: [u64; CALLERS],
+    magic: u64,
+}
+
+struct LiveBlocks {
+    head: *mut Header,
+    count: usize,
+    bytes: usize,
+}
+
+unsafe impl Send for LiveBlocks {}
+
+static LIVE_BLOCKS: Spinlock<LiveBlocks> = Spinlock::new(LiveBlocks {
+    head: ptr::null_mut(),
+    count: 0,
+    bytes: 0,
+});
+
+/// A live allocation, for `for_each_live`
+pub struct LiveBlock {
+    pub address: u64,
+    pub size: usize,
+    pub callers: [u64; CALLERS],
+}
+
+/// Offset of the allocation from the start of its block
+fn offset(layout: Layout) -> usize {
+    let align = layout.align();
+    (core::mem::size_of::<Header>() + REDZONE + align - 1) & !(align - 1)
+}
+
+/// Layout of the block holding an allocation of `layout`
+pub fn block_layout(layout: Layout) -> Layout {
+    let align = layout.align().max(core::mem::align_of::<Header>());
+    Layout::from_size_align(offset(layout) + layout.size() + REDZONE, align)
+        .expect("Unable to lay out debug heap block!")
+}
+
+/// Whether an allocation has a debug header, the block can't start below `lowest`. Blocks from
+/// before tracking started can't overlap a header, so they never carry a valid magic.
+pub unsafe fn tracked(data: *mut u8, layout: Layout, lowest: usize) -> bool {
+    let block = match (data as usize).checked_sub(offset(layout)) {
+        Some(block) if block >= lowest => block as *const Header,
+        _ => return false,
+    };
+    matches!((*block).magic, LIVE | FREED)
+}
+
+/// Return addresses of the callers, found by following the frame pointers
+#[inline(always)]
+fn callers() -> [u64; CALLERS] {
+    let mut callers = [0; CALLERS];
+    let mut rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack)) };
+
+    for caller in callers.iter_mut() {
+        if rbp == 0 || rbp % 8 != 0 {
+            break;
+        }
+        let (next, ret) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        *caller = ret;
+        if next <= rbp || next - rbp > FRAME_LIMIT {
+            break;
+        }
+        rbp = next;
+    }
+    callers
+}
+
+/// Sets up a fresh block and returns the allocation inside it
+#[inline(always)]
+pub unsafe fn init(block: *mut u8, layout: Layout) -> *mut u8 {
+    let offset = offset(layout);
+    let data = block.add(offset);
+    let header = block as *mut Header;
+    let header_size = core::mem::size_of::<Header>();
+
+    ptr::write_bytes(block.add(header_size), CANARY, offset - header_size);
+    ptr::write_bytes(data, UNINITIALIZED, layout.size());
+    ptr::write_bytes(data.add(layout.size()), CANARY, REDZONE);
+
+    let mut live = LIVE_BLOCKS.lock();
+    header.write(Header {
+        prev: ptr::null_mut(),
+        next: live.head,
+        size: layout.size(),
+        align: layout.align(),
+        callers: callers(),
+        magic: LIVE,
+    });
+    if !live.head.is_null() {
+        (*live.head).prev = header;
+    }
+    live.head = header;
+    live.count += 1;
+    live.bytes += layout.size();
+    data
+}
+
+/// Checks an allocation that is about to be freed and poisons it. Returns its block.
+pub unsafe fn release(data: *mut u8, layout: Layout) -> *mut u8 {
+    let offset = offset(layout);
+    let block = data.sub(offset);
+    let header = &mut *(block as *mut Header);
+
+    match header.magic {
+        LIVE => (),
+        FREED => panic!("Double free of {:p}", data),
+        _ => panic!("Heap corruption: header of {:p} overwritten", data),
+    }
+    if header.size != layout.size() {
+        panic!(
+            "Freeing {:p} with size {}, it was allocated with {} from {:x?}",
+            data,
+            layout.size(),
+            header.size,
+            header.callers
+        );
+    }
+
+    let header_size = core::mem::size_of::<Header>();
+    let front = core::slice::from_raw_parts(block.add(header_size), offset - header_size);
+    let back = core::slice::from_raw_parts(data.add(layout.size()), REDZONE);
+    if front.iter().any(|&b| b != CANARY) {
+        panic!("Heap corruption: underflow of {:p} allocated from {:x?}", data, header.callers);
+    }
+    if back.iter().any(|&b| b != CANARY) {
+        panic!("Heap corruption: overflow of {:p} allocated from {:x?}", data, header.callers);
+    }
+
+    let mut live = LIVE_BLOCKS.lock();
+    if header.prev.is_null() {
+        live.head = header.next;
+    } else {
+        (*header.prev).next = header.next;
+    }
+    if !header.next.is_null() {
+        (*header.next).prev = header.prev;
+    }
+    live.count -= 1;
+    live.bytes -= layout.size();
+    drop(live);
+
+    header.magic = FREED;
+    ptr::write_bytes(data, POISON, layout.size());
+    block
+}
+
+/// Calls `f` for every live allocation, newest first. `f` must not allocate.
+pub fn for_each_live(mut f: impl FnMut(&LiveBlock)) {
+    let live = LIVE_BLOCKS.lock();
+    let mut header = live.head;
+    while !header.is_null() {
+        unsafe {
+            let layout = Layout::from_size_align_unchecked((*header).size, (*header).align);
+            f(&LiveBlock {
+                address: header as u64 + offset(layout) as u64,
+                size: (*header).size,
+                callers: (*header).callers,
+            });
+            header = (*header).next;
+        }
+    }
+}
+
+/// Number and total size of live allocations
+pub fn live() -> (usize, usize) {
+    let live = LIVE_BLOCKS.lock();
+    (live.count, live.bytes)
+}
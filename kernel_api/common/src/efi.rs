@@ -7,7 +7,7 @@ pub type Handle = usize;
 
 const EMPTY_HANDLE: Handle = 0;
 
-const BUFFER_TOO_SMALL: usize = 5 | (1 << 63);
+pub const BUFFER_TOO_SMALL: usize = 5 | (1 << 63);
 
 #[repr(C)]
 struct TableHeader {
@@ -24,9 +24,9 @@ pub struct SystemTable {
     vendor: *const Char16,
     revision: u32,
     console_in_handle: Handle,
-    console_in: *const u8,
+    console_in: *const SimpleTextInputProtocol,
     console_out_handle: Handle,
-    console_out: Handle,
+    console_out: *const SimpleTextOutputProtocol,
     console_error_handle: Handle,
     console_error: *const u8,
     runtime_services: *const RuntimeServices,
@@ -47,6 +47,14 @@ impl SystemTable {
     pub fn runtime_services(&self) -> &RuntimeServices {
         unsafe { &*self.runtime_services }
     }
+
+    pub fn console_in(&self) -> &SimpleTextInputProtocol {
+        unsafe { &*self.console_in }
+    }
+
+    pub fn console_out(&self) -> &SimpleTextOutputProtocol {
+        unsafe { &*self.console_out }
+    }
 }
 
 #[repr(C)]
@@ -95,8 +103,8 @@ pub struct RuntimeServices {
     /*
     Time services
     */
-    get_time: Handle,
-    set_time: Handle,
+    get_time: extern "efiapi" fn(*mut Time, *mut TimeCapabilities) -> usize,
+    set_time: extern "efiapi" fn(*const Time) -> usize,
     get_wakeup_time: Handle,
     set_wakeup_time: Handle,
 
@@ -106,8 +114,30 @@ pub struct RuntimeServices {
     set_virtual_address_map:
         extern "efiapi" fn(usize, usize, u32, *const MemoryDescriptor) -> usize,
     convert_pointer: extern "efiapi" fn() -> usize,
+
+    /*
+    Variable services
+    */
+    get_variable: extern "efiapi" fn(
+        *const Char16,
+        *const guid::GUID,
+        *mut u32,
+        *mut usize,
+        *mut u8,
+    ) -> usize,
+    get_next_variable_name: extern "efiapi" fn(*mut usize, *mut Char16, *mut guid::GUID) -> usize,
+    set_variable:
+        extern "efiapi" fn(*const Char16, *const guid::GUID, u32, usize, *const u8) -> usize,
+
+    /*
+    Miscellaneous services
+    */
+    get_next_high_monotonic_count: Handle,
+    reset_system: extern "efiapi" fn(ResetType, usize, usize, *const ()) -> !,
 }
 
+/// The raw calls below aren't reentrant and have to run with the firmware's runtime regions mapped,
+/// the kernel goes through its own locked wrappers instead of calling them directly.
 impl RuntimeServices {
     pub fn set_virtual_address_map(&self, map: MemoryMap<'_>, version: u32) -> usize {
         let map_size = core::mem::size_of_val(map);
@@ -115,6 +145,75 @@ impl RuntimeServices {
         let map_ptr = map.as_ptr();
         (self.set_virtual_address_map)(map_size, entry_size, version, map_ptr)
     }
+
+    pub unsafe fn get_time(&self, time: &mut Time) -> usize {
+        (self.get_time)(time, core::ptr::null_mut())
+    }
+
+    pub unsafe fn set_time(&self, time: &Time) -> usize {
+        (self.set_time)(time)
+    }
+
+    /// `name` has to be null terminated. On return `size` holds the size of the variable.
+    pub unsafe fn get_variable(
+        &self,
+        name: &[Char16],
+        vendor: &guid::GUID,
+        attributes: &mut u32,
+        size: &mut usize,
+        buffer: &mut [u8],
+    ) -> usize {
+        *size = buffer.len();
+        (self.get_variable)(name.as_ptr(), vendor, attributes, size, buffer.as_mut_ptr())
+    }
+
+    /// `name` holds the previous name on entry (empty string to start) and the next one on return
+    pub unsafe fn get_next_variable_name(
+        &self,
+        name: &mut [Char16],
+        vendor: &mut guid::GUID,
+    ) -> usize {
+        let mut size = core::mem::size_of_val(name);
+        (self.get_next_variable_name)(&mut size, name.as_mut_ptr(), vendor)
+    }
+
+    /// Deletes the variable if `data` is empty
+    pub unsafe fn set_variable(
+        &self,
+        name: &[Char16],
+        vendor: &guid::GUID,
+        attributes: u32,
+        data: &[u8],
+    ) -> usize {
+        (self.set_variable)(name.as_ptr(), vendor, attributes, data.len(), data.as_ptr())
+    }
+
+    pub unsafe fn reset_system(&self, reset_type: ResetType, status: usize) -> ! {
+        (self.reset_system)(reset_type, status, 0, core::ptr::null())
+    }
+}
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy)]
+pub enum ResetType {
+    Cold,
+    Warm,
+    Shutdown,
+    PlatformSpecific,
+}
+
+pub const VARIABLE_NON_VOLATILE: u32 = 0x1;
+pub const VARIABLE_BOOTSERVICE_ACCESS: u32 = 0x2;
+pub const VARIABLE_RUNTIME_ACCESS: u32 = 0x4;
+
+pub const NOT_FOUND: usize = 14 | (1 << 63);
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TimeCapabilities {
+    pub resolution: u32,
+    pub accuracy: u32,
+    pub sets_to_zero: bool,
 }
 
 #[repr(C)]
@@ -174,7 +273,7 @@ pub struct BootServices {
     Miscellaneaous Services
     */
     get_next_monotonic_count: Handle,
-    stall: Handle,
+    stall: extern "efiapi" fn(usize) -> usize,
     set_watchdog_timer: extern "efiapi" fn(usize, u64, usize, *const Char16) -> usize,
 
     /*
@@ -247,6 +346,40 @@ impl BootServices {
     pub fn set_watchdog_timer(&self, timeout: usize, watchdog_code: u64) -> usize {
         (self.set_watchdog_timer)(timeout, watchdog_code, 0, core::ptr::null())
     }
+
+    pub fn stall(&self, microseconds: usize) -> usize {
+        (self.stall)(microseconds)
+    }
+
+    pub fn locate_protocol<T>(&self, protocol: &guid::GUID, interface: &mut *const T) -> usize {
+        let ptr = interface as *mut *const T;
+        (self.locate_protocol)(protocol, core::ptr::null(), ptr as *mut *const ())
+    }
+}
+
+#[repr(C)]
+pub struct RngProtocol {
+    get_info: Handle,
+    get_rng: extern "efiapi" fn(*const RngProtocol, *const guid::GUID, usize, *mut u8) -> usize,
+}
+
+impl RngProtocol {
+    /// Fills `buffer` using the firmware's default RNG algorithm
+    pub fn get_rng(&self, buffer: &mut [u8]) -> usize {
+        (self.get_rng)(self, core::ptr::null(), buffer.len(), buffer.as_mut_ptr())
+    }
+}
+
+/// The firmware RNG, if the platform has one. Only usable before boot services are exited.
+pub fn rng_protocol() -> Option<&'static RngProtocol> {
+    let mut rng: *const RngProtocol = core::ptr::null();
+    let res = get_system_table()
+        .boot_services()
+        .locate_protocol(&guid::RNG_PROTOCOL, &mut rng);
+    if res != 0 || rng.is_null() {
+        return None;
+    }
+    Some(unsafe { &*rng })
 }
 
 #[repr(C)]
@@ -297,18 +430,18 @@ pub struct FileProtocol {
 }
 
 #[repr(C, packed)]
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, Copy)]
 pub struct Time {
-    year: u16,
-    month: u8,
-    day: u8,
-    hour: u8,
-    minute: u8,
-    second: u8,
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
     pad1: u8,
-    nanosecond: u32,
-    time_zone: i16,
-    daylight: u8,
+    pub nanosecond: u32,
+    pub time_zone: i16,
+    pub daylight: u8,
     pad2: u8,
 }
 
@@ -371,6 +504,57 @@ pub fn io_volume(image_handle: Handle) -> *const FileIOInterface {
     }
 }
 
+/// Reads a whole file from the volume the loader was started from into pool memory. `path` has to
+/// be null terminated.
+pub fn read_file(image_handle: Handle, path: &[Char16]) -> Option<&'static [u8]> {
+    let volume = unsafe { &*io_volume(image_handle) };
+    let mut root: *const FileProtocol = core::ptr::null();
+    let res = (volume.open_volume)(volume as _, &mut root);
+    if res != 0 {
+        kprintln!("An error occured! {:x} OpenVolume(SFSP)", res);
+        return None;
+    }
+    let root = unsafe { &*root };
+
+    let mut file: *const FileProtocol = core::ptr::null();
+    let res = (root.open)(root, &mut file, path.as_ptr(), FILE_MODE_READ, FILE_READ_ONLY);
+    (root.close)(root);
+    if res != 0 {
+        kprintln!("An error occured! {:x} OPEN(SFSP)", res);
+        return None;
+    }
+    let file = unsafe { &*file };
+
+    let mut file_info: FileInfo = unsafe { core::mem::zeroed() };
+    let mut size = core::mem::size_of::<FileInfo>();
+    let res = (file.get_info)(file, &guid::FILE_INFO, &mut size, &mut file_info);
+    if res != 0 {
+        kprintln!("An error occured! {:x} GETINFO(SFSP)", res);
+        (file.close)(file);
+        return None;
+    }
+
+    let mut file_data: *mut u8 = core::ptr::null_mut();
+    let res = get_system_table()
+        .boot_services()
+        .allocate_pool(file_info.file_size, &mut file_data);
+    if res != 0 {
+        kprintln!("An error occured! {:x} ALLOCATEPOOL(SFSP)", res);
+        (file.close)(file);
+        return None;
+    }
+
+    let data = unsafe { core::slice::from_raw_parts_mut(file_data, file_info.file_size) };
+    let res = read_fixed(file, 0, file_info.file_size, data);
+    (file.close)(file);
+    if res != 0 {
+        kprintln!("An error occured! {:x} READ (SFSP)", res);
+        return None;
+    }
+
+    Some(data)
+}
+
 pub fn read_fixed(file: &FileProtocol, offset: usize, size: usize, buffer: &mut [u8]) -> usize {
     let mut read = 0usize;
 
@@ -498,19 +682,101 @@ impl MemoryDescriptor {
 
 pub type MemoryMap<'a> = &'a [MemoryDescriptor];
 
-// #[repr(C)]
-// pub struct SimpleTextOutputProtocol {
-//     reset: extern "efiapi" fn(*mut Self),
-//     output_string: extern "efiapi" fn(*mut Self, *const u16),
-//     test_string: extern "efiapi" fn(&Self),
-//     query_mode: extern "efiapi" fn(&Self),
-//     set_mode: extern "efiapi" fn(&Self),
-//     set_attribute: extern "efiapi" fn(&Self),
-//     clear_screen: extern "efiapi" fn(&Self),
-//     set_cursor_position: extern "efiapi" fn(&Self),
-//     enable_cursor: extern "efiapi" fn(&Self),
-//     mode: *const u8,
-// }
+#[repr(C)]
+pub struct SimpleTextOutputProtocol {
+    reset: extern "efiapi" fn(*const Self, bool) -> usize,
+    output_string: extern "efiapi" fn(*const Self, *const Char16) -> usize,
+    test_string: Handle,
+    query_mode: Handle,
+    set_mode: Handle,
+    set_attribute: Handle,
+    clear_screen: extern "efiapi" fn(*const Self) -> usize,
+    set_cursor_position: Handle,
+    enable_cursor: extern "efiapi" fn(*const Self, bool) -> usize,
+    mode: *const u8,
+}
+
+impl SimpleTextOutputProtocol {
+    pub fn clear_screen(&self) -> usize {
+        (self.clear_screen)(self)
+    }
+
+    pub fn enable_cursor(&self, visible: bool) -> usize {
+        (self.enable_cursor)(self, visible)
+    }
+
+    /// Prints `string`, translating `\n` to `\r\n`
+    pub fn output(&self, string: &str) -> usize {
+        let mut buffer = [0 as Char16; 65];
+        let mut len = 0;
+        for c in string.chars() {
+            if c == '\n' {
+                buffer[len] = '\r' as Char16;
+                len += 1;
+            }
+            let mut units = [0u16; 2];
+            for unit in c.encode_utf16(&mut units) {
+                buffer[len] = *unit;
+                len += 1;
+            }
+
+            if len >= buffer.len() - 4 {
+                buffer[len] = 0;
+                let res = (self.output_string)(self, buffer.as_ptr());
+                if res != 0 {
+                    return res;
+                }
+                len = 0;
+            }
+        }
+        buffer[len] = 0;
+        (self.output_string)(self, buffer.as_ptr())
+    }
+}
+
+impl core::fmt::Write for &SimpleTextOutputProtocol {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        match self.output(s) {
+            0 => Ok(()),
+            _ => Err(core::fmt::Error),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct InputKey {
+    pub scan_code: u16,
+    pub unicode_char: Char16,
+}
+
+impl InputKey {
+    pub const SCAN_UP: u16 = 0x01;
+    pub const SCAN_DOWN: u16 = 0x02;
+    pub const SCAN_ESC: u16 = 0x17;
+}
+
+#[repr(C)]
+pub struct SimpleTextInputProtocol {
+    reset: extern "efiapi" fn(*const Self, bool) -> usize,
+    read_key_stroke: extern "efiapi" fn(*const Self, *mut InputKey) -> usize,
+    wait_for_key: Handle,
+}
+
+impl SimpleTextInputProtocol {
+    pub fn reset(&self) -> usize {
+        (self.reset)(self, false)
+    }
+
+    /// The next pending key stroke, doesn't block
+    pub fn read_key_stroke(&self) -> Option<InputKey> {
+        let mut key = InputKey::default();
+        match (self.read_key_stroke)(self, &mut key) {
+            0 => Some(key),
+            _ => None,
+        }
+    }
+}
 
 pub static GLOBAL_SYSTEM_TABLE: AtomicPtr<SystemTable> = AtomicPtr::new(core::ptr::null_mut());
 
@@ -643,7 +909,7 @@ pub mod guid {
     use alloc::fmt::format;
     pub use macros::create_guid;
 
-    #[derive(PartialEq)]
+    #[derive(PartialEq, Clone, Copy)]
     pub struct GUID {
         a: u32,
         /// The middle field of the timestamp.
@@ -694,4 +960,14 @@ pub mod guid {
     pub const RSDP: GUID = create_guid!(8868E871-E4F1-11D3-BC22-0080C73C8881);
 
     pub const FILE_INFO: GUID = create_guid!(09576e92-6d3f-11d2-8e39-00a0c969723b);
+
+    pub const GLOBAL_VARIABLE: GUID = create_guid!(8BE4DF61-93CA-11d2-AA0D-00E098032B8C);
+
+    // 3152bca5-eade-433d-862e-c01cdc291f44, "862e" doesn't lex so it's spelled out
+    pub const RNG_PROTOCOL: GUID = GUID {
+        a: 0x3152bca5,
+        b: 0xeade,
+        c: 0x433d,
+        d: [0x86, 0x2e, 0xc0, 0x1c, 0xdc, 0x29, 0x1f, 0x44],
+    };
 }
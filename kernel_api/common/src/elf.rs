@@ -1,4 +1,4 @@
-use core::{marker::PhantomData, fmt::{Formatter, write, Error, Debug}};
+use core::marker::PhantomData;
 
 
 #[repr(u16)]
@@ -22,7 +22,7 @@ pub enum Machine {
 }
 
 #[repr(u16)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum FileType {
     None = 0,
     Relocatable,
@@ -70,30 +70,185 @@ pub struct Header {
 }
 
 impl Header {
+    /// Position independent executables are `SharedObject`s and can be loaded at any address
+    pub fn is_position_independent(&self) -> bool {
+        let file_type = self.file_type;
+        matches!(file_type, FileType::SharedObject)
+    }
+
+    pub fn file_type(&self) -> FileType {
+        self.file_type
+    }
+
+    /// Header of an x86_64 core file with `program_headers` program headers right after it
+    pub fn core(program_headers: u16) -> Header {
+        Header {
+            ident: u32::from_le_bytes(*b"\x7fELF"),
+            bits: BitSize::X64,
+            endianess: Endianess::Little,
+            // EI_VERSION then EI_OSABI
+            abi: 1,
+            header_version: 0,
+            _reserved: 0,
+            file_type: FileType::Core,
+            machine: Machine::Amd64,
+            version: 1,
+            entry: 0,
+            prg_header_tbl: core::mem::size_of::<Header>() as u64,
+            sec_header_tbl: 0,
+            flags: 0,
+            header_size: core::mem::size_of::<Header>() as u16,
+            prg_entry_size: core::mem::size_of::<ProgramHeader>() as u16,
+            prg_entry_count: program_headers,
+            sec_entry_size: 0,
+            sec_entry_count: 0,
+            sec_str_index: 0,
+        }
+    }
+
     pub fn program_header_table(&self) -> *const ProgramHeader {
         unsafe { (self as *const Header as *const u8).offset(0 as _) as *const ProgramHeader } 
     }
 }
 
+#[derive(Debug)]
+pub enum ElfError {
+    Truncated,
+    BadMagic,
+    UnsupportedClass(u8),
+    UnsupportedEndianess(u8),
+    UnsupportedMachine(u16),
+    UnsupportedType(u16),
+    BadProgramHeaders,
+    /// A segment's file range lies outside the file or is larger than its memory size
+    BadSegment(usize),
+    BadSectionHeaders,
+}
+
 pub struct ElfFile<'a> {
     pub data: &'a [u8]
 }
 
+/// Null terminated string at `offset` in a string table
+fn c_string(table: &[u8], offset: usize) -> Option<&str> {
+    let data = table.get(offset..)?;
+    let len = data.iter().position(|&b| b == 0)?;
+    core::str::from_utf8(&data[..len]).ok()
+}
+
+/// SysV ELF hash used by `DT_HASH`
+fn elf_hash(name: &str) -> u32 {
+    let mut h = 0u32;
+    for &b in name.as_bytes() {
+        h = (h << 4).wrapping_add(b as u32);
+        let g = h & 0xF0000000;
+        if g != 0 {
+            h ^= g >> 24;
+        }
+        h &= !g;
+    }
+    h
+}
+
+/// djb2 variant used by `DT_GNU_HASH`
+fn gnu_hash(name: &str) -> u32 {
+    name.as_bytes()
+        .iter()
+        .fold(5381u32, |h, &b| h.wrapping_mul(33).wrapping_add(b as u32))
+}
+
+/// `offset..offset + size` if it lies within `len` bytes
+fn checked_range(offset: u64, size: u64, len: usize) -> Option<core::ops::Range<usize>> {
+    let end = offset.checked_add(size)?;
+    if end > len as u64 {
+        return None;
+    }
+    Some(offset as usize..end as usize)
+}
+
 impl <'a> ElfFile<'a> {
-    pub fn new(data: &'a [u8]) -> ElfFile {
-        ElfFile { data }
+    /// Validates everything the accessors below rely on, so they can't read outside `data` or
+    /// produce invalid enum values
+    pub fn parse(data: &'a [u8]) -> Result<ElfFile<'a>, ElfError> {
+        if data.len() < core::mem::size_of::<Header>() {
+            return Err(ElfError::Truncated);
+        }
+        if data[0..4] != *b"\x7fELF" {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != BitSize::X64 as u8 {
+            return Err(ElfError::UnsupportedClass(data[4]));
+        }
+        if data[5] != Endianess::Little as u8 {
+            return Err(ElfError::UnsupportedEndianess(data[5]));
+        }
+        let file_type = u16::from_le_bytes([data[16], data[17]]);
+        if !(FileType::Relocatable as u16..=FileType::Core as u16).contains(&file_type) {
+            return Err(ElfError::UnsupportedType(file_type));
+        }
+        let machine = u16::from_le_bytes([data[18], data[19]]);
+        if machine != Machine::Amd64 as u16 {
+            return Err(ElfError::UnsupportedMachine(machine));
+        }
+
+        let elf = ElfFile { data };
+        let header = elf.header();
+
+        let count = header.prg_entry_count as u64;
+        if count > 0 {
+            if header.prg_entry_size as usize != core::mem::size_of::<ProgramHeader>() {
+                return Err(ElfError::BadProgramHeaders);
+            }
+            checked_range(
+                header.prg_header_tbl,
+                count * core::mem::size_of::<ProgramHeader>() as u64,
+                data.len(),
+            )
+            .ok_or(ElfError::BadProgramHeaders)?;
+        }
+
+        for (i, pheader) in elf.progam_headers().enumerate() {
+            let in_file = checked_range(pheader.offset, pheader.segment_file_size, data.len());
+            if in_file.is_none() || pheader.segment_file_size > pheader.segment_mem_size {
+                return Err(ElfError::BadSegment(i));
+            }
+            if pheader
+                .virtual_address
+                .checked_add(pheader.segment_mem_size)
+                .is_none()
+            {
+                return Err(ElfError::BadSegment(i));
+            }
+        }
+
+        let count = header.sec_entry_count as u64;
+        if count > 0 {
+            if header.sec_entry_size as usize != core::mem::size_of::<SectionHeader>()
+                || header.sec_str_index as u64 >= count
+            {
+                return Err(ElfError::BadSectionHeaders);
+            }
+            checked_range(
+                header.sec_header_tbl,
+                count * core::mem::size_of::<SectionHeader>() as u64,
+                data.len(),
+            )
+            .ok_or(ElfError::BadSectionHeaders)?;
+        }
+
+        Ok(elf)
     }
 
     pub fn header(&self) -> &Header {
+        // Checked by `parse`
         unsafe {
-            &*(&self.data[0] as *const u8 as *const _)
+            &*(self.data.as_ptr() as *const Header)
         }
     }
 
     pub fn progam_headers(&self) -> ProgramHeaderIterator {
         let header = self.header();
-        let base = unsafe { &self.data[header.prg_header_tbl as usize] as *const u8 as *const ProgramHeader };
-        assert!(header.prg_entry_size as usize == core::mem::size_of::<ProgramHeader>() || header.prg_entry_count == 0, "Sizes aren't equal {} == {}", header.prg_entry_size, core::mem::size_of::<ProgramHeader>());
+        let base = self.data.as_ptr().wrapping_add(header.prg_header_tbl as usize) as *const ProgramHeader;
         ProgramHeaderIterator {
             base,
             index: 0,
@@ -109,33 +264,297 @@ impl <'a> ElfFile<'a> {
             None
         }
     }
+
+    /// The `PT_TLS` segment, the initialization image for each thread's TLS block
+    pub fn tls(&self) -> Option<&ProgramHeader> {
+        self.progam_headers()
+            .find(|p| p.segment_type() == SegmentType::Tls)
+    }
+
+    /// File offset of a virtual address that is backed by one of the load segments
+    pub fn virtual_to_offset(&self, address: u64) -> Option<usize> {
+        self.progam_headers()
+            .filter(|p| p.segment_type() == SegmentType::Load)
+            .find(|p| {
+                address >= p.virtual_address && address < p.virtual_address + p.segment_file_size
+            })
+            .map(|p| (address - p.virtual_address + p.offset) as usize)
+    }
+
+    /// Entries of the `PT_DYNAMIC` segment up to the terminating `Dynamic::NULL`
+    pub fn dynamic(&self) -> Option<impl Iterator<Item = &Dynamic>> {
+        let header = self
+            .progam_headers()
+            .find(|p| p.segment_type() == SegmentType::Dynamic)?;
+        let data = self.segment(header)?;
+
+        let entries = EntryIterator::new(
+            data.as_ptr() as *const Dynamic,
+            data.len() / core::mem::size_of::<Dynamic>(),
+        );
+        Some(entries.take_while(|d| d.tag != Dynamic::NULL))
+    }
+
+    pub fn dynamic_value(&self, tag: i64) -> Option<u64> {
+        self.dynamic()?.find(|d| d.tag == tag).map(|d| d.value)
+    }
+
+    /// A table the dynamic section points to with an address and a size entry
+    fn dynamic_table<T>(&self, address_tag: i64, size_tag: i64) -> EntryIterator<T> {
+        let table = self.dynamic_value(address_tag).zip(self.dynamic_value(size_tag));
+        match table {
+            Some((address, size)) => match self
+                .virtual_to_offset(address)
+                .and_then(|offset| checked_range(offset as u64, size, self.data.len()))
+            {
+                Some(range) => EntryIterator::new(
+                    self.data[range.start..].as_ptr() as *const T,
+                    size as usize / core::mem::size_of::<T>(),
+                ),
+                None => EntryIterator::new(core::ptr::null(), 0),
+            },
+            None => EntryIterator::new(core::ptr::null(), 0),
+        }
+    }
+
+    /// All `Rela` relocations, including the PLT ones
+    pub fn relocations(&self) -> impl Iterator<Item = &Rela> {
+        self.dynamic_table::<Rela>(Dynamic::RELA, Dynamic::RELASZ)
+            .chain(self.dynamic_table::<Rela>(Dynamic::JMPREL, Dynamic::PLTRELSZ))
+    }
+
+    pub fn section_headers(&self) -> EntryIterator<SectionHeader> {
+        let header = self.header();
+        let base = self.data.as_ptr().wrapping_add(header.sec_header_tbl as usize);
+        EntryIterator::new(base as *const SectionHeader, header.sec_entry_count as usize)
+    }
+
+    /// Contents of a section, `None` for sections without file data like `.bss`
+    pub fn section_data(&self, section: &SectionHeader) -> Option<&[u8]> {
+        if section.section_type == SectionHeader::NOBITS {
+            return None;
+        }
+        self.data
+            .get(checked_range(section.offset, section.size, self.data.len())?)
+    }
+
+    pub fn section_name(&self, section: &SectionHeader) -> Option<&str> {
+        let strings = self
+            .section_headers()
+            .nth(self.header().sec_str_index as usize)?;
+        self.string(strings, section.name)
+    }
+
+    pub fn section_by_name(&self, name: &str) -> Option<&SectionHeader> {
+        self.section_headers()
+            .find(|s| self.section_name(s) == Some(name))
+    }
+
+    /// Null terminated string at `offset` in a string table section
+    pub fn string(&self, table: &SectionHeader, offset: u32) -> Option<&str> {
+        c_string(self.section_data(table)?, offset as usize)
+    }
+
+    /// Entries of a `RELA` section, their offsets are relative to section `info`
+    pub fn section_relocations(&self, section: &SectionHeader) -> EntryIterator<Rela> {
+        match self.section_data(section) {
+            Some(data) if section.section_type == SectionHeader::RELA => EntryIterator::new(
+                data.as_ptr() as *const Rela,
+                data.len() / core::mem::size_of::<Rela>(),
+            ),
+            _ => EntryIterator::new(core::ptr::null(), 0),
+        }
+    }
+
+    /// The first section of a type, `.symtab` is `SYMTAB` and `.dynsym` is `DYNSYM`
+    pub fn section_of_type(&self, section_type: u32) -> Option<&SectionHeader> {
+        self.section_headers()
+            .find(|s| s.section_type == section_type)
+    }
+
+    /// Symbols of `.symtab`, or of `.dynsym` if the file was stripped
+    pub fn symbols(&self) -> Option<SymbolTable> {
+        let table = self
+            .section_of_type(SectionHeader::SYMTAB)
+            .or_else(|| self.section_of_type(SectionHeader::DYNSYM))?;
+        let data = self.section_data(table)?;
+        let strings = self.section_headers().nth(table.link as usize)?;
+
+        Some(SymbolTable {
+            elf: self,
+            strings,
+            symbols: EntryIterator::new(
+                data.as_ptr() as *const Symbol,
+                data.len() / core::mem::size_of::<Symbol>(),
+            ),
+        })
+    }
+
+    /// Function or object containing `address` and the offset into it. Addresses are link
+    /// addresses, subtract the load slide first.
+    pub fn resolve(&self, address: u64) -> Option<(&str, u64)> {
+        let table = self.symbols()?;
+        let mut best: Option<&Symbol> = None;
+
+        for symbol in table.symbols.clone() {
+            if !symbol.is_function() && !symbol.is_object() {
+                continue;
+            }
+            let (start, size) = (symbol.value, symbol.size);
+            if address < start {
+                continue;
+            }
+            if size > 0 && address < start.saturating_add(size) {
+                best = Some(symbol);
+                break;
+            }
+            // Hand written assembly often has no size, take the closest symbol below
+            if size == 0 && best.map_or(true, |b| { b.value } < start) {
+                best = Some(symbol);
+            }
+        }
+
+        let symbol = best?;
+        Some((table.name(symbol)?, address - { symbol.value }))
+    }
+
+    /// String in the `DT_STRTAB` table, used for symbol and library names
+    pub fn dynamic_string(&self, offset: u32) -> Option<&str> {
+        let table = self.virtual_to_offset(self.dynamic_value(Dynamic::STRTAB)?)?;
+        let size = self.dynamic_value(Dynamic::STRSZ)?;
+        let strings = &self.data[checked_range(table as u64, size, self.data.len())?];
+        c_string(strings, offset as usize)
+    }
+
+    /// Libraries the image was linked against (`DT_NEEDED`)
+    pub fn needed(&self) -> impl Iterator<Item = &str> {
+        self.dynamic()
+            .into_iter()
+            .flatten()
+            .filter(|d| d.tag == Dynamic::NEEDED)
+            .filter_map(|d| self.dynamic_string(d.value as u32))
+    }
+
+    /// 32 bit word of a hash table at virtual address `address`
+    fn hash_word(&self, address: u64, index: u64) -> Option<u32> {
+        let address = index.checked_mul(4).and_then(|i| address.checked_add(i))?;
+        let offset = self.virtual_to_offset(address)?;
+        let bytes = self.data.get(offset..offset.checked_add(4)?)?;
+        Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Finds a symbol defined by this image through its `DT_GNU_HASH` or `DT_HASH` table
+    pub fn lookup_dynamic(&self, name: &str) -> Option<&Symbol> {
+        let matches = |index: u32| {
+            self.dynamic_symbol(index)
+                .filter(|s| !s.is_undefined() && self.dynamic_string(s.name) == Some(name))
+        };
+
+        if let Some(table) = self.dynamic_value(Dynamic::GNU_HASH) {
+            let bucket_count = self.hash_word(table, 0)? as u64;
+            let symbol_offset = self.hash_word(table, 1)?;
+            let bloom_size = self.hash_word(table, 2)? as u64;
+            if bucket_count == 0 {
+                return None;
+            }
+            // Skip the header and the 64 bit bloom filter words
+            let buckets = table.checked_add(16)?.checked_add(bloom_size.checked_mul(8)?)?;
+            let chains = buckets.checked_add(bucket_count.checked_mul(4)?)?;
+
+            let hash = gnu_hash(name);
+            let mut index = self.hash_word(buckets, hash as u64 % bucket_count)?;
+            if index < symbol_offset {
+                return None;
+            }
+            loop {
+                let chain_hash = self.hash_word(chains, (index - symbol_offset) as u64)?;
+                if chain_hash | 1 == hash | 1 {
+                    if let Some(symbol) = matches(index) {
+                        return Some(symbol);
+                    }
+                }
+                if chain_hash & 1 != 0 {
+                    return None;
+                }
+                index = index.checked_add(1)?;
+            }
+        }
+
+        let table = self.dynamic_value(Dynamic::HASH)?;
+        let bucket_count = self.hash_word(table, 0)? as u64;
+        let chain_count = self.hash_word(table, 1)?;
+        if bucket_count == 0 {
+            return None;
+        }
+        let buckets = table.checked_add(8)?;
+        let chains = buckets.checked_add(bucket_count.checked_mul(4)?)?;
+
+        let mut index = self.hash_word(buckets, elf_hash(name) as u64 % bucket_count)?;
+        // The chain count bounds the walk in case the table loops
+        for _ in 0..chain_count {
+            if index == 0 {
+                break;
+            }
+            if let Some(symbol) = matches(index) {
+                return Some(symbol);
+            }
+            index = self.hash_word(chains, index as u64)?;
+        }
+        None
+    }
+
+    pub fn dynamic_symbol(&self, index: u32) -> Option<&Symbol> {
+        let symtab = self.dynamic_value(Dynamic::SYMTAB)?;
+        let offset = (index as usize)
+            .checked_mul(core::mem::size_of::<Symbol>())
+            .and_then(|i| self.virtual_to_offset(symtab)?.checked_add(i))?;
+        let bytes = self.data.get(offset..offset.checked_add(core::mem::size_of::<Symbol>())?)?;
+        Some(unsafe { &*(bytes.as_ptr() as *const Symbol) })
+    }
 }
 
-#[repr(u32)]
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SegmentType {
-    Null = 0,
+    Null,
     Load,
     Dynamic,
     Interpret,
     Note,
     Reserved,
-    ProgramHeader
+    ProgramHeader,
+    Tls,
+    /// OS or processor specific, e.g. `PT_GNU_STACK`
+    Other(u32),
+}
+
+impl From<SegmentType> for u32 {
+    fn from(value: SegmentType) -> Self {
+        match value {
+            SegmentType::Null => 0,
+            SegmentType::Load => 1,
+            SegmentType::Dynamic => 2,
+            SegmentType::Interpret => 3,
+            SegmentType::Note => 4,
+            SegmentType::Reserved => 5,
+            SegmentType::ProgramHeader => 6,
+            SegmentType::Tls => 7,
+            SegmentType::Other(other) => other,
+        }
+    }
 }
 
-impl Debug for SegmentType {
-    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
-        match *self as u32 {
-            0 => write(f, format_args!("Null")),
-            1 => write(f, format_args!("Load")),
-            2 => write(f, format_args!("Dynamic")),
-            3 => write(f, format_args!("Interpret")),
-            4 => write(f, format_args!("Note")),
-            5 => write(f, format_args!("Reserved")),
-            6 => write(f, format_args!("ProgramHeader")),
-            0x70000000.. => write(f, format_args!("Lo Proc")),
-            0x7FFFFFFF.. => write(f, format_args!("High Proc")),
-            _ => write!(f, "Unknown Segment Type"),
+impl From<u32> for SegmentType {
+    fn from(value: u32) -> Self {
+        match value {
+            0 => SegmentType::Null,
+            1 => SegmentType::Load,
+            2 => SegmentType::Dynamic,
+            3 => SegmentType::Interpret,
+            4 => SegmentType::Note,
+            5 => SegmentType::Reserved,
+            6 => SegmentType::ProgramHeader,
+            7 => SegmentType::Tls,
+            other => SegmentType::Other(other),
         }
     }
 }
@@ -151,7 +570,7 @@ pub enum ProgramHeaderFlags {
 #[repr(C, packed)]
 #[derive(Debug)]
 pub struct ProgramHeader {
-    pub segement_type: SegmentType,
+    segment_type: u32,
     pub flags: u32,
     pub offset: u64,
     pub virtual_address: u64,
@@ -161,6 +580,48 @@ pub struct ProgramHeader {
     _align: u64
 }
 
+impl ProgramHeader {
+    pub fn new(
+        segment_type: SegmentType,
+        flags: u32,
+        offset: u64,
+        virtual_address: u64,
+        segment_file_size: u64,
+        segment_mem_size: u64,
+    ) -> ProgramHeader {
+        ProgramHeader {
+            segment_type: segment_type.into(),
+            flags,
+            offset,
+            virtual_address,
+            _reserved: 0,
+            segment_file_size,
+            segment_mem_size,
+            _align: 0,
+        }
+    }
+
+    pub fn align(&self) -> u64 {
+        self._align
+    }
+
+    pub fn segment_type(&self) -> SegmentType {
+        SegmentType::from(self.segment_type)
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.flags & ProgramHeaderFlags::Executable as u32 != 0
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.flags & ProgramHeaderFlags::Writable as u32 != 0
+    }
+
+    pub fn is_readable(&self) -> bool {
+        self.flags & ProgramHeaderFlags::Readable as u32 != 0
+    }
+}
+
 pub struct ProgramHeaderIterator<'a> {
     base: *const ProgramHeader,
     index: usize,
@@ -183,3 +644,237 @@ impl <'a> Iterator for ProgramHeaderIterator<'a> {
         }
     }
 }
+
+/// Iterates a table of packed entries
+#[derive(Clone)]
+pub struct EntryIterator<'a, T> {
+    base: *const T,
+    index: usize,
+    size: usize,
+    pd: PhantomData<&'a T>,
+}
+
+impl<'a, T> EntryIterator<'a, T> {
+    fn new(base: *const T, size: usize) -> EntryIterator<'a, T> {
+        EntryIterator {
+            base,
+            index: 0,
+            size,
+            pd: PhantomData,
+        }
+    }
+}
+
+impl<'a, T: 'a> Iterator for EntryIterator<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index < self.size {
+            let res = unsafe { &*self.base.offset(self.index as _) };
+            self.index += 1;
+            Some(res)
+        } else {
+            None
+        }
+    }
+}
+
+#[repr(C, packed)]
+#[derive(Debug)]
+pub struct Dynamic {
+    pub tag: i64,
+    pub value: u64,
+}
+
+impl Dynamic {
+    pub const NULL: i64 = 0;
+    pub const NEEDED: i64 = 1;
+    pub const PLTRELSZ: i64 = 2;
+    pub const PLTGOT: i64 = 3;
+    pub const HASH: i64 = 4;
+    pub const STRTAB: i64 = 5;
+    pub const SYMTAB: i64 = 6;
+    pub const RELA: i64 = 7;
+    pub const RELASZ: i64 = 8;
+    pub const RELAENT: i64 = 9;
+    pub const STRSZ: i64 = 10;
+    pub const SYMENT: i64 = 11;
+    pub const PLTREL: i64 = 20;
+    pub const JMPREL: i64 = 23;
+    pub const GNU_HASH: i64 = 0x6FFFFEF5;
+}
+
+#[repr(C, packed)]
+#[derive(Debug)]
+pub struct Rela {
+    pub offset: u64,
+    pub info: u64,
+    pub addend: i64,
+}
+
+impl Rela {
+    pub const X86_64_NONE: u32 = 0;
+    pub const X86_64_64: u32 = 1;
+    pub const X86_64_PC32: u32 = 2;
+    pub const X86_64_PLT32: u32 = 4;
+    pub const X86_64_GLOB_DAT: u32 = 6;
+    pub const X86_64_JUMP_SLOT: u32 = 7;
+    pub const X86_64_RELATIVE: u32 = 8;
+    pub const X86_64_GOTPCREL: u32 = 9;
+    pub const X86_64_32: u32 = 10;
+    pub const X86_64_32S: u32 = 11;
+    pub const X86_64_PC64: u32 = 24;
+    pub const X86_64_GOTPCRELX: u32 = 41;
+    pub const X86_64_REX_GOTPCRELX: u32 = 42;
+
+    pub fn symbol(&self) -> u32 {
+        (self.info >> 32) as u32
+    }
+
+    pub fn relocation_type(&self) -> u32 {
+        (self.info & 0xFFFFFFFF) as u32
+    }
+}
+
+#[repr(C, packed)]
+#[derive(Debug)]
+pub struct Symbol {
+    pub name: u32,
+    pub info: u8,
+    pub other: u8,
+    pub section_index: u16,
+    pub value: u64,
+    pub size: u64,
+}
+
+impl Symbol {
+    pub const TYPE_OBJECT: u8 = 1;
+    pub const TYPE_FUNC: u8 = 2;
+    pub const TYPE_SECTION: u8 = 3;
+
+    /// Special `section_index` values
+    pub const INDEX_ABS: u16 = 0xFFF1;
+    pub const INDEX_COMMON: u16 = 0xFFF2;
+
+    pub const BIND_LOCAL: u8 = 0;
+    pub const BIND_GLOBAL: u8 = 1;
+    pub const BIND_WEAK: u8 = 2;
+
+    pub fn is_undefined(&self) -> bool {
+        self.section_index == 0
+    }
+
+    pub fn is_weak(&self) -> bool {
+        self.binding() == Symbol::BIND_WEAK
+    }
+
+    pub fn symbol_type(&self) -> u8 {
+        self.info & 0xF
+    }
+
+    pub fn binding(&self) -> u8 {
+        self.info >> 4
+    }
+
+    pub fn is_function(&self) -> bool {
+        self.symbol_type() == Symbol::TYPE_FUNC
+    }
+
+    pub fn is_object(&self) -> bool {
+        self.symbol_type() == Symbol::TYPE_OBJECT
+    }
+}
+
+pub struct SymbolTable<'a> {
+    elf: &'a ElfFile<'a>,
+    strings: &'a SectionHeader,
+    symbols: EntryIterator<'a, Symbol>,
+}
+
+impl<'a> SymbolTable<'a> {
+    pub fn name(&self, symbol: &Symbol) -> Option<&'a str> {
+        self.elf.string(self.strings, symbol.name)
+    }
+
+    pub fn iter(&self) -> EntryIterator<'a, Symbol> {
+        self.symbols.clone()
+    }
+
+    pub fn get(&self, index: u32) -> Option<&'a Symbol> {
+        if (index as usize) < self.symbols.size {
+            Some(unsafe { &*self.symbols.base.add(index as usize) })
+        } else {
+            None
+        }
+    }
+
+    pub fn find(&self, name: &str) -> Option<&'a Symbol> {
+        self.iter().find(|s| self.name(s) == Some(name))
+    }
+}
+
+#[repr(C, packed)]
+#[derive(Debug)]
+pub struct SectionHeader {
+    pub name: u32,
+    pub section_type: u32,
+    pub flags: u64,
+    pub address: u64,
+    pub offset: u64,
+    pub size: u64,
+    pub link: u32,
+    pub info: u32,
+    pub align: u64,
+    pub entry_size: u64,
+}
+
+impl SectionHeader {
+    pub const NULL: u32 = 0;
+    pub const PROGBITS: u32 = 1;
+    pub const SYMTAB: u32 = 2;
+    pub const STRTAB: u32 = 3;
+    pub const RELA: u32 = 4;
+    pub const HASH: u32 = 5;
+    pub const DYNAMIC: u32 = 6;
+    pub const NOTE: u32 = 7;
+    pub const NOBITS: u32 = 8;
+    pub const REL: u32 = 9;
+    pub const DYNSYM: u32 = 11;
+
+    pub const FLAG_WRITE: u64 = 1;
+    pub const FLAG_ALLOC: u64 = 2;
+    pub const FLAG_EXECINSTR: u64 = 4;
+    pub const FLAG_TLS: u64 = 0x400;
+}
+
+/// Header of an entry in a `PT_NOTE` segment, followed by the name and the descriptor, each padded
+/// to 4 bytes
+#[repr(C)]
+#[derive(Debug)]
+pub struct NoteHeader {
+    pub name_size: u32,
+    pub desc_size: u32,
+    pub note_type: u32,
+}
+
+impl NoteHeader {
+    pub const PRSTATUS: u32 = 1;
+}
+
+#[derive(Debug)]
+pub enum RelocationError {
+    Unsupported(u32),
+    UndefinedSymbol(u32),
+    Unmapped(u64),
+    /// The value doesn't fit the relocation's field, at the given address
+    Overflow(u64),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum LoadError {
+    /// The segment at the given link address doesn't fit the address space once slid
+    BadAddress(u64),
+    /// A page of the segment is already mapped by a segment other than the one before
+    Overlap(u64),
+    OutOfMemory,
+}
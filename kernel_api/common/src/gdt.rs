@@ -19,6 +19,8 @@ struct Selectors {
     tss_selector: gdt::SegmentSelector,
 }
 static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+/// Stack the CPU switches to for exceptions from user mode
+static mut PRIVILEGE_STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
 
 lazy_static! {
     static ref TSS: TaskStateSegment = {
@@ -30,6 +32,8 @@ lazy_static! {
             let stack_end = stack_start + STACK_SIZE;
             stack_end
         };
+        tss.privilege_stack_table[0] =
+            VirtAddr::from_ptr(unsafe { &PRIVILEGE_STACK }) + STACK_SIZE;
         tss
     };
 }
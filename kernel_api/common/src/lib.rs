@@ -9,10 +9,17 @@ pub mod elf;
 pub mod util;
 pub mod serial;
 pub mod gdt;
+pub mod cpu;
 pub mod mem;
 pub mod allocator;
 pub mod process;
 pub mod memory_regions;
+pub mod memory_map;
+pub mod mmio;
+pub mod vma;
+pub mod slab;
+#[cfg(feature = "debug_heap")]
+pub mod debug_heap;
 mod linked_list_allocator;
 
 use core::fmt::Debug;
@@ -25,18 +32,26 @@ use x86_64::structures::paging::PageTable;
 extern crate alloc;
 
 pub struct KernelParameters<'a> {
-    pub memory_map: &'a [efi::MemoryDescriptor],
     // Physical address of boot image
     pub boot_image: (u64, u64),
-    pub frame_allocator: PageTableFrameAllocator<'a>,
+    pub frame_allocator: PageTableFrameAllocator,
     pub system_table: *mut SystemTable,
     // pub heap_top: usize,
     pub heap: linked_list_allocator::Heap,
+    pub layout: memory_regions::MemoryLayout,
+    pub command_line: &'a str,
+    // Wait for a debugger before doing anything
+    pub debug: bool,
     // pub page_table: PageTable,
 }
 
 impl Debug for KernelParameters<'_> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        f.debug_struct("KernelParameters").field("boot_image", &self.boot_image).finish()
+        f.debug_struct("KernelParameters")
+            .field("boot_image", &self.boot_image)
+            .field("layout", &self.layout)
+            .field("command_line", &self.command_line)
+            .field("debug", &self.debug)
+            .finish()
     }
 }
\ No newline at end of file
@@ -1,32 +1,41 @@
-use core::{
-    iter::{Filter, FlatMap, Map, StepBy},
-    mem::{size_of, size_of_val},
-    ops::Range,
-    slice::Iter,
-};
+use alloc::collections::BTreeMap;
+use core::mem::{size_of, size_of_val};
 
 use spinning_top::{lock_api::MutexGuard, RawSpinlock, Spinlock};
 use x86_64::{
+    instructions::tlb,
+    registers::model_specific::{Efer, EferFlags},
     structures::paging::{
         mapper::{MapToError, MapperFlush, MapperFlushAll},
-        FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags,
-        PhysFrame, Size4KiB,
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable,
+        PageTableEntry, PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
     },
     PhysAddr, VirtAddr,
 };
 
-use crate::{efi::{self, MemoryDescriptor}, memory_regions::PAGE_TABLE_OFFSET};
+use crate::{
+    memory_map::{PhysicalMemoryMap, Region, RegionKind},
+    memory_regions,
+    process::ProcessId,
+    slab,
+};
 
 pub const STACK_SIZE: usize = 4096 * 5;
 
+/// Physical address of the level 4 table of the kernel address space
 pub static mut KERNEL_MAP: u64 = 0x0;
 
-static mut ALLOCATOR: Option<Spinlock<PageTableFrameAllocator<'static>>> = None;
+static mut ALLOCATOR: Option<Spinlock<PageTableFrameAllocator>> = None;
 
-pub fn allocator() -> &'static mut Spinlock<PageTableFrameAllocator<'static>> {
+pub fn allocator() -> &'static mut Spinlock<PageTableFrameAllocator> {
     unsafe { ALLOCATOR.as_mut().unwrap() }
 }
 
+/// Where physical memory is mapped in the current address space
+pub fn physical_offset() -> u64 {
+    allocator().lock().physical_offset()
+}
+
 pub unsafe fn active_level_4_table() -> &'static mut PageTable {
     use x86_64::registers::control::Cr3;
 
@@ -46,88 +55,324 @@ pub fn active_offset_page_table(offset: u64) -> OffsetPageTable<'static> {
     }
 }
 
-pub fn init(alloc: PageTableFrameAllocator<'static>, offset: u64) -> OffsetPageTable<'static> {
+/// Page tables of the kernel address space, whichever address space is active
+pub fn kernel_page_table() -> OffsetPageTable<'static> {
+    let offset = physical_offset();
+    unsafe {
+        let level_4_table = &mut *((offset + KERNEL_MAP) as *mut PageTable);
+        OffsetPageTable::new(level_4_table, VirtAddr::new(offset))
+    }
+}
+
+/// Sets EFER.NXE if the processor supports no-execute pages. Returns whether NX can be used.
+pub fn enable_nx() -> bool {
+    let extended_features = unsafe { core::arch::x86_64::__cpuid(0x8000_0001) };
+    if extended_features.edx & (1 << 20) == 0 {
+        return false;
+    }
+
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+    }
+    true
+}
+
+/// Whether `PageTableFlags::NO_EXECUTE` may be set without faulting on a reserved bit
+pub fn nx_enabled() -> bool {
+    Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE)
+}
+
+/// Whether the processor supports 1GiB pages
+pub fn gigabyte_pages() -> bool {
+    let extended_features = unsafe { core::arch::x86_64::__cpuid(0x8000_0001) };
+    extended_features.edx & (1 << 26) != 0
+}
+
+/// Sets up the frame allocator, the active address space becomes the kernel's
+pub fn init(mut alloc: PageTableFrameAllocator, offset: u64) -> OffsetPageTable<'static> {
+    alloc.set_physical_offset(offset);
     unsafe {
         ALLOCATOR.replace(Spinlock::new(alloc));
+        KERNEL_MAP = x86_64::registers::control::Cr3::read().0.start_address().as_u64();
     }
 
     active_offset_page_table(offset)
 }
 
-pub fn map_virt<'a, S>(
+pub fn map_virt(phys: PhysAddr, virt: VirtAddr, size: usize) -> Result<(), MapToError<Size4KiB>> {
+    let mut pt = active_offset_page_table(memory_regions::physmap_base());
+    map_range(
+        &mut pt,
+        phys,
+        virt,
+        size as u64,
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+        &mut slab::page_tables(),
+    )
+}
+
+/// Identity maps write-back memory, device memory goes through `mmio::ioremap`
+pub fn map_phys(phys: PhysAddr, size: usize) -> Result<(), MapToError<Size4KiB>> {
+    let mut pt = active_offset_page_table(memory_regions::physmap_base());
+    map_phys_table(&mut pt, phys, size)
+}
+
+pub fn map_phys_table(
+    pgtbl: &mut OffsetPageTable<'_>,
     phys: PhysAddr,
-    virt: VirtAddr,
     size: usize,
-) -> Result<(), MapToError<S>>
-where
-    S: PageSize, OffsetPageTable<'a>: Mapper<S>
-{
-    let mut pt = active_offset_page_table(PAGE_TABLE_OFFSET);
-    let start = PhysFrame::containing_address(phys);
-    let end = PhysFrame::containing_address(phys + size);
-
-    let pg_start = Page::containing_address(virt);
-    let pg_end = Page::containing_address(virt + size);
-
-    for (frame, page) in PhysFrame::<S>::range_inclusive(start, end)
-        .zip(Page::<S>::range_inclusive(pg_start, pg_end))
-    {
-        match unsafe {
-            <OffsetPageTable as Mapper<S>>::map_to(
-                &mut pt,
-                page,
-                frame,
-                PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
-                allocator().get_mut(),
-            )
-        } {
-            Ok(o) => o.flush(),
-            Err(e) => return Err(e),
+) -> Result<(), MapToError<Size4KiB>> {
+    kprintln!("Mapping {:x}", phys.as_u64());
+    map_range(
+        pgtbl,
+        phys,
+        VirtAddr::new(phys.as_u64()),
+        size as u64,
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+        &mut slab::page_tables(),
+    )
+}
+
+/* Bytes an entry covers at each level, indexed by level - 1 */
+const LEVEL_SIZES: [u64; 4] = [Size4KiB::SIZE, Size2MiB::SIZE, Size1GiB::SIZE, size_gb!(512)];
+
+/// Largest page size `phys` and `virt` are both aligned to that fits in `size`, 1GiB pages only
+/// if `gigabyte` is set
+fn largest_page(phys: u64, virt: u64, size: u64, gigabyte: bool) -> u64 {
+    [Size1GiB::SIZE, Size2MiB::SIZE]
+        .into_iter()
+        .filter(|&page| gigabyte || page != Size1GiB::SIZE)
+        .find(|&page| phys % page == 0 && virt % page == 0 && size >= page)
+        .unwrap_or(Size4KiB::SIZE)
+}
+
+fn map_error<S: PageSize>(error: MapToError<S>) -> MapToError<Size4KiB> {
+    match error {
+        MapToError::FrameAllocationFailed => MapToError::FrameAllocationFailed,
+        MapToError::ParentEntryHugePage => MapToError::ParentEntryHugePage,
+        MapToError::PageAlreadyMapped(frame) => {
+            MapToError::PageAlreadyMapped(PhysFrame::containing_address(frame.start_address()))
         }
     }
+}
+
+fn table_at(offset: VirtAddr, address: PhysAddr) -> &'static mut PageTable {
+    unsafe { &mut *(offset + address.as_u64()).as_mut_ptr::<PageTable>() }
+}
+
+/// Maps `[phys, phys + size)` at `virt` with the largest pages alignment allows, smaller ones at
+/// the edges. Whatever was mapped in the range before is replaced, huge pages reaching past its
+/// edges are split first.
+pub fn map_range(
+    mapper: &mut OffsetPageTable,
+    phys: PhysAddr,
+    virt: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<(), MapToError<Size4KiB>> {
+    let page_offset = phys.as_u64() % Size4KiB::SIZE;
+    let mut phys = phys.align_down(Size4KiB::SIZE).as_u64();
+    let mut virt = virt.align_down(Size4KiB::SIZE);
+    let end = (virt + page_offset + size).align_up(Size4KiB::SIZE);
+    let gigabyte = gigabyte_pages();
+
+    clear(mapper, virt.as_u64(), end.as_u64(), allocator)?;
+
+    while virt < end {
+        let page = largest_page(phys, virt.as_u64(), end - virt, gigabyte);
+        let frame = PhysAddr::new(phys);
+        unsafe {
+            if page == Size1GiB::SIZE {
+                mapper
+                    .map_to(
+                        Page::<Size1GiB>::containing_address(virt),
+                        PhysFrame::containing_address(frame),
+                        flags,
+                        allocator,
+                    )
+                    .map_err(map_error)?
+                    .ignore();
+            } else if page == Size2MiB::SIZE {
+                mapper
+                    .map_to(
+                        Page::<Size2MiB>::containing_address(virt),
+                        PhysFrame::containing_address(frame),
+                        flags,
+                        allocator,
+                    )
+                    .map_err(map_error)?
+                    .ignore();
+            } else {
+                mapper
+                    .map_to(
+                        Page::<Size4KiB>::containing_address(virt),
+                        PhysFrame::containing_address(frame),
+                        flags,
+                        allocator,
+                    )?
+                    .ignore();
+            }
+        }
+        phys += page;
+        virt += page;
+    }
+    tlb::flush_all();
     Ok(())
 }
 
-pub fn map_phys(phys: PhysAddr, size: usize) -> Result<(), MapToError<Size4KiB>> {
-    let mut pt = active_offset_page_table(PAGE_TABLE_OFFSET);
-    let start = PhysFrame::containing_address(phys);
-    let end = PhysFrame::containing_address(phys + size);
-    for frame in PhysFrame::<Size4KiB>::range_inclusive(start, end) {
-        match unsafe {
-            <OffsetPageTable as Mapper<Size4KiB>>::identity_map(
-                &mut pt,
-                frame,
-                PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
-                allocator().get_mut(),
-            )
-        } {
-            Ok(o) => o.flush(),
-            Err(e) => return Err(e),
+/// Unmaps everything in `[virt, virt + size)`, splitting huge pages that reach past its edges.
+/// Frames aren't freed, page tables the range covers completely are.
+pub fn unmap_range(
+    mapper: &mut OffsetPageTable,
+    virt: VirtAddr,
+    size: u64,
+    allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<(), MapToError<Size4KiB>> {
+    let start = virt.align_down(Size4KiB::SIZE).as_u64();
+    let end = (virt + size).align_up(Size4KiB::SIZE).as_u64();
+    clear(mapper, start, end, allocator)?;
+    tlb::flush_all();
+    Ok(())
+}
+
+/// Unmaps the page aligned range `[start, end)` without flushing the TLB
+fn clear(
+    mapper: &mut OffsetPageTable,
+    start: u64,
+    end: u64,
+    allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<(), MapToError<Size4KiB>> {
+    split_huge(mapper, start, allocator)?;
+    split_huge(mapper, end, allocator)?;
+
+    // Table indices only see the low 48 bits
+    let mask = (1 << 48) - 1;
+    let offset = mapper.phys_offset();
+    clear_range(
+        offset,
+        mapper.level_4_table(),
+        4,
+        0,
+        start & mask,
+        ((end - 1) & mask) + 1,
+        allocator,
+    );
+    Ok(())
+}
+
+/// Page tables the range covers completely go back to `allocator`, which allocated them
+fn clear_range(
+    offset: VirtAddr,
+    table: &mut PageTable,
+    level: usize,
+    base: u64,
+    start: u64,
+    end: u64,
+    allocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    let entry_size = LEVEL_SIZES[level - 1];
+    for (index, entry) in table.iter_mut().enumerate() {
+        let entry_start = base + index as u64 * entry_size;
+        let entry_end = entry_start + entry_size;
+        if entry_end <= start || entry_start >= end || entry.is_unused() {
+            continue;
+        }
+
+        if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            entry.set_unused();
+            continue;
+        }
+
+        clear_range(
+            offset,
+            table_at(offset, entry.addr()),
+            level - 1,
+            entry_start,
+            start,
+            end,
+            allocator,
+        );
+        if start <= entry_start && entry_end <= end {
+            let frame = PhysFrame::containing_address(entry.addr());
+            entry.set_unused();
+            unsafe { allocator.deallocate_frame(frame) };
         }
     }
+}
+
+/// Splits the huge page `address` is inside of, if it doesn't start there, into pages of the
+/// next smaller size
+fn split_huge(
+    mapper: &mut OffsetPageTable,
+    address: u64,
+    allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let offset = mapper.phys_offset();
+    let virt = VirtAddr::new_truncate(address);
+
+    let l4_entry = &mut mapper.level_4_table()[virt.p4_index()];
+    if l4_entry.is_unused() {
+        return Ok(());
+    }
+    let l3_entry = &mut table_at(offset, l4_entry.addr())[virt.p3_index()];
+    if l3_entry.is_unused() {
+        return Ok(());
+    }
+    if l3_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        if address % Size1GiB::SIZE == 0 {
+            return Ok(());
+        }
+        split_entry(offset, l3_entry, Size2MiB::SIZE, allocator)?;
+    }
+
+    let l2_entry = &mut table_at(offset, l3_entry.addr())[virt.p2_index()];
+    if l2_entry.flags().contains(PageTableFlags::HUGE_PAGE) && address % Size2MiB::SIZE != 0 {
+        split_entry(offset, l2_entry, Size4KiB::SIZE, allocator)?;
+    }
     Ok(())
 }
 
-pub fn map_phys_table(
-    pgtbl: &mut OffsetPageTable<'_>,
-    phys: PhysAddr,
-    size: usize,
+/// PAT bit of a huge page entry, among the address bits
+const HUGE_PAT: u64 = 1 << 12;
+/// PAT bit of a 4KiB entry
+const SMALL_PAT: PageTableFlags = PageTableFlags::HUGE_PAGE;
+
+/// Replaces a huge page entry with a table of 512 pages of `page_size` mapping the same memory
+fn split_entry(
+    offset: VirtAddr,
+    entry: &mut PageTableEntry,
+    page_size: u64,
+    allocator: &mut impl FrameAllocator<Size4KiB>,
 ) -> Result<(), MapToError<Size4KiB>> {
-    kprintln!("Mapping {:x}", phys.as_u64());
-    let start = PhysFrame::containing_address(phys);
-    let end = PhysFrame::containing_address(phys + size);
-    for frame in PhysFrame::<Size4KiB>::range_inclusive(start, end) {
-        match unsafe {
-            pgtbl.identity_map(
-                PhysFrame::<Size4KiB>::containing_address(phys),
-                PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
-                allocator().get_mut(),
-            )
-        } {
-            Ok(o) => o.flush(),
-            Err(e) => return Err(e),
+    let frame = allocator
+        .allocate_frame()
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    let table = table_at(offset, frame.start_address());
+
+    // Bit 12 of a huge entry is its PAT bit, 4KiB entries have theirs where HUGE_PAGE is
+    let pat = entry.addr().as_u64() & HUGE_PAT != 0;
+    let base = entry.addr().as_u64() & !HUGE_PAT;
+    let mut flags = entry.flags();
+    let mut pat_address = 0;
+    if page_size == Size4KiB::SIZE {
+        flags -= PageTableFlags::HUGE_PAGE;
+        if pat {
+            flags |= SMALL_PAT;
         }
+    } else if pat {
+        pat_address = HUGE_PAT;
     }
+    for (index, page) in table.iter_mut().enumerate() {
+        let address = base + index as u64 * page_size;
+        page.set_addr(PhysAddr::new(address | pat_address), flags);
+    }
+
+    // The pages keep the restrictions, the table entry has to allow everything they do
+    let table_flags = entry.flags()
+        & (PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE);
+    // Same translations as before, the caller flushes once it is done with the range
+    entry.set_addr(frame.start_address(), table_flags | PageTableFlags::PRESENT);
     Ok(())
 }
 
@@ -181,127 +426,480 @@ pub fn map_arr_table<T>(
     )
 }
 
+const FRAME_SIZE: u64 = Size4KiB::SIZE;
+
+/// Physical address ranges some allocations have to come from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    /// Below 1MiB, for AP trampolines and legacy DMA
+    Low,
+    /// Below 4GiB, for devices that can only address 32 bits
+    Dma32,
+    /// Anywhere, preferring memory above 4GiB so the zones above stay free
+    Normal,
+}
+
+impl Zone {
+    /// Frame ranges to search in order. Frame 0 is never handed out, a null physical address is
+    /// always a bug.
+    fn ranges(&self, frames: usize) -> [(usize, usize); 2] {
+        let low = (size_mb!(1) / FRAME_SIZE) as usize;
+        let dma32 = (size_gb!(4) / FRAME_SIZE) as usize;
+        match self {
+            Zone::Low => [(1, low), (0, 0)],
+            Zone::Dma32 => [(low, dma32), (1, low)],
+            Zone::Normal => [(dma32, frames), (low, dma32)],
+        }
+    }
+}
+
+/// What allocated frames are used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Owner {
+    Kernel,
+    Heap,
+    PageTable,
+    Process(ProcessId),
+    Dma,
+    BootImage,
+    Module,
+}
+
+impl Owner {
+    const KINDS: [&'static str; 7] = [
+        "Kernel",
+        "Heap",
+        "Page tables",
+        "Processes",
+        "DMA",
+        "Boot image",
+        "Modules",
+    ];
+
+    fn index(&self) -> usize {
+        match self {
+            Owner::Kernel => 0,
+            Owner::Heap => 1,
+            Owner::PageTable => 2,
+            Owner::Process(_) => 3,
+            Owner::Dma => 4,
+            Owner::BootImage => 5,
+            Owner::Module => 6,
+        }
+    }
+}
+
+/// Processes tracked individually, the rest only count towards `Owner::Process` as a whole
+const MAX_TRACKED_PROCESSES: usize = 64;
+
+/// Frames in use by each owner
 #[derive(Clone)]
-pub struct PageTableFrameAllocator<'a> {
-    memory_map: efi::MemoryMap<'a>,
-    addresses: Map<
-        FlatMap<
-            Map<
-                Filter<Iter<'a, MemoryDescriptor>, fn(&&MemoryDescriptor) -> bool>,
-                fn(&MemoryDescriptor) -> Range<usize>,
-            >,
-            StepBy<Range<usize>>,
-            fn(Range<usize>) -> StepBy<Range<usize>>,
-        >,
-        fn(usize) -> PhysFrame<Size4KiB>,
-    >,
-}
-
-impl<'a> PageTableFrameAllocator<'a> {
-    pub fn swap_map(&mut self, memory_map: efi::MemoryMap<'a>) {
-        let curr_frame = self.allocate_frame();
-        kprintln!("Frame {:?}", curr_frame);
-        let iter = memory_map.iter();
-        let usable: Filter<Iter<MemoryDescriptor>, fn(&&MemoryDescriptor) -> bool> =
-            iter.filter(|d| d.memory_type.is_usable());
-
-        let mut address_range: Map<
-            Filter<Iter<MemoryDescriptor>, fn(&&MemoryDescriptor) -> bool>,
-            fn(&MemoryDescriptor) -> Range<usize>,
-        > = usable.map(|u| u.physical_address..(u.physical_address + u.size * 4096));
-        // address_range.(|f| {f.start < curr_frame.unwrap().start_address().as_u64() as usize});
-        while address_range.next().unwrap().start
-            < curr_frame.unwrap().start_address().as_u64() as usize
-        {}
-        let addresses: FlatMap<
-            Map<
-                Filter<Iter<MemoryDescriptor>, fn(&&MemoryDescriptor) -> bool>,
-                fn(&MemoryDescriptor) -> Range<usize>,
-            >,
-            StepBy<Range<usize>>,
-            fn(Range<usize>) -> StepBy<Range<usize>>,
-        > = address_range.flat_map(|r| r.step_by(4096));
-
-        let amap: Map<
-            FlatMap<
-                Map<
-                    Filter<Iter<MemoryDescriptor>, fn(&&MemoryDescriptor) -> bool>,
-                    fn(&MemoryDescriptor) -> Range<usize>,
-                >,
-                StepBy<Range<usize>>,
-                fn(Range<usize>) -> StepBy<Range<usize>>,
-            >,
-            fn(usize) -> PhysFrame<Size4KiB>,
-        > = addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr as u64)));
-
-        self.addresses = amap;
-    }
-
-    pub fn new(memory_map: efi::MemoryMap<'a>) -> Self {
-        let iter = memory_map.iter();
-        let usable: Filter<Iter<MemoryDescriptor>, fn(&&MemoryDescriptor) -> bool> =
-            iter.filter(|d| d.memory_type.is_usable());
-
-        let address_range: Map<
-            Filter<Iter<MemoryDescriptor>, fn(&&MemoryDescriptor) -> bool>,
-            fn(&MemoryDescriptor) -> Range<usize>,
-        > = usable.map(|u| u.physical_address..(u.physical_address + u.size * 4096));
-        let addresses: FlatMap<
-            Map<
-                Filter<Iter<MemoryDescriptor>, fn(&&MemoryDescriptor) -> bool>,
-                fn(&MemoryDescriptor) -> Range<usize>,
-            >,
-            StepBy<Range<usize>>,
-            fn(Range<usize>) -> StepBy<Range<usize>>,
-        > = address_range.flat_map(|r| r.step_by(4096));
-
-        let amap: Map<
-            FlatMap<
-                Map<
-                    Filter<Iter<MemoryDescriptor>, fn(&&MemoryDescriptor) -> bool>,
-                    fn(&MemoryDescriptor) -> Range<usize>,
-                >,
-                StepBy<Range<usize>>,
-                fn(Range<usize>) -> StepBy<Range<usize>>,
-            >,
-            fn(usize) -> PhysFrame<Size4KiB>,
-        > = addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr as u64)));
-
-        PageTableFrameAllocator {
+pub struct Usage {
+    owners: [usize; Owner::KINDS.len()],
+    processes: [(ProcessId, usize); MAX_TRACKED_PROCESSES],
+}
+
+impl Usage {
+    const fn new() -> Usage {
+        Usage {
+            owners: [0; Owner::KINDS.len()],
+            processes: [(0, 0); MAX_TRACKED_PROCESSES],
+        }
+    }
+
+    fn charge(&mut self, owner: Owner, count: usize) {
+        self.owners[owner.index()] += count;
+        if let Owner::Process(id) = owner {
+            let slot = match self.processes.iter().position(|&(p, n)| p == id && n != 0) {
+                Some(slot) => Some(slot),
+                None => self.processes.iter().position(|&(_, n)| n == 0),
+            };
+            if let Some(slot) = slot {
+                self.processes[slot] = (id, self.processes[slot].1 + count);
+            }
+        }
+    }
+
+    fn uncharge(&mut self, owner: Owner, count: usize) {
+        let index = owner.index();
+        self.owners[index] = self.owners[index].saturating_sub(count);
+        if let Owner::Process(id) = owner {
+            if let Some(entry) = self.processes.iter_mut().find(|(p, n)| *p == id && *n != 0) {
+                entry.1 = entry.1.saturating_sub(count);
+            }
+        }
+    }
+
+    /// Bytes in use by `owner`, for `Owner::Process` only by that process
+    pub fn of(&self, owner: Owner) -> u64 {
+        let frames = match owner {
+            Owner::Process(id) => self
+                .processes
+                .iter()
+                .find(|&&(p, n)| p == id && n != 0)
+                .map_or(0, |&(_, n)| n),
+            _ => self.owners[owner.index()],
+        };
+        frames as u64 * FRAME_SIZE
+    }
+
+    /// Bytes in use by every process together
+    pub fn processes(&self) -> u64 {
+        self.owners[Owner::Process(0).index()] as u64 * FRAME_SIZE
+    }
+}
+
+/// Bitmap frame allocator, one bit per frame of physical memory with set bits for frames in use.
+/// The bitmap lives in physical memory taken from the map, it is reached through the loader's
+/// identity mapping at first and through the physical memory mapping once `mem::init` sets the
+/// offset, so copies of the allocator share it.
+#[derive(Clone)]
+pub struct PageTableFrameAllocator {
+    memory_map: PhysicalMemoryMap,
+    bitmap: u64,
+    frames: usize,
+    /// Every frame below this is in use
+    hint: usize,
+    free: usize,
+    physical_offset: u64,
+    usage: Usage,
+    /// Frames mapped by more than one address space, by physical address
+    shared: BTreeMap<u64, SharedFrame>,
+}
+
+/// Extra references to a frame, like private memory shared copy-on-write after a fork, and the
+/// owner it is charged to. The entry stays after the last extra reference is dropped so the frame
+/// is still uncharged from that owner when it is freed.
+#[derive(Clone, Copy)]
+struct SharedFrame {
+    refs: usize,
+    owner: Owner,
+}
+
+impl PageTableFrameAllocator {
+    /// Builds the bitmap in the first usable region above 1MiB big enough for it. Physical memory
+    /// has to be identity mapped.
+    pub fn new(memory_map: PhysicalMemoryMap) -> Self {
+        let frames = (memory_map.highest_address() / FRAME_SIZE) as usize;
+        let bitmap_size = ((frames + 63) / 64 * 8) as u64;
+        let bitmap_size = (bitmap_size + FRAME_SIZE - 1) & !(FRAME_SIZE - 1);
+
+        let bitmap = memory_map
+            .usable()
+            .map(|r| Region {
+                start: r.start.max(size_mb!(1)),
+                ..*r
+            })
+            .find(|r| r.end > r.start && r.size() >= bitmap_size)
+            .expect("No room for the frame bitmap!")
+            .start;
+
+        let mut allocator = PageTableFrameAllocator {
             memory_map,
-            addresses: amap,
+            bitmap,
+            frames,
+            hint: 0,
+            free: 0,
+            physical_offset: 0,
+            usage: Usage::new(),
+            shared: BTreeMap::new(),
+        };
+
+        allocator.words().fill(u64::MAX);
+        for i in 0..allocator.memory_map.regions().len() {
+            let region = allocator.memory_map.regions()[i];
+            if region.kind == RegionKind::Usable {
+                allocator.free_region(&region);
+            }
+        }
+        allocator.mark_used(0, 1);
+        allocator.mark_used(
+            (bitmap / FRAME_SIZE) as usize,
+            (bitmap_size / FRAME_SIZE) as usize,
+        );
+        allocator
+    }
+
+    /// Where physical memory is mapped in the current address space, the bitmap is accessed
+    /// through it
+    pub fn set_physical_offset(&mut self, offset: u64) {
+        self.physical_offset = offset;
+    }
+
+    pub fn physical_offset(&self) -> u64 {
+        self.physical_offset
+    }
+
+    fn words(&mut self) -> &mut [u64] {
+        unsafe {
+            core::slice::from_raw_parts_mut(
+                (self.physical_offset + self.bitmap) as *mut u64,
+                (self.frames + 63) / 64,
+            )
         }
     }
 
-    pub fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> + 'a {
-        let iter = self.memory_map.iter();
-        let usable = iter.filter(|d| d.memory_type.is_usable());
+    fn is_used(&mut self, index: usize) -> bool {
+        self.words()[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    fn mark_used(&mut self, start: usize, count: usize) {
+        for index in start..(start + count).min(self.frames) {
+            if !self.is_used(index) {
+                self.words()[index / 64] |= 1 << (index % 64);
+                self.free -= 1;
+            }
+        }
+        if (start..start + count).contains(&self.hint) {
+            self.hint = start + count;
+        }
+    }
+
+    fn mark_free(&mut self, start: usize, count: usize) {
+        for index in start..(start + count).min(self.frames) {
+            if self.is_used(index) {
+                self.words()[index / 64] &= !(1 << (index % 64));
+                self.free += 1;
+            }
+        }
+        self.hint = self.hint.min(start);
+    }
+
+    fn free_region(&mut self, region: &Region) {
+        let start = ((region.start + FRAME_SIZE - 1) / FRAME_SIZE) as usize;
+        let end = (region.end / FRAME_SIZE) as usize;
+        if end > start {
+            self.mark_free(start, end - start);
+        }
+    }
+
+    /// First free run of `count` frames in `[start, end)` starting on a multiple of `align` frames
+    fn find(&mut self, start: usize, end: usize, count: usize, align: usize) -> Option<usize> {
+        let end = end.min(self.frames);
+        let mut index = (start.max(self.hint) + align - 1) / align * align;
+
+        while index + count <= end {
+            // Skip whole words of used frames
+            if index % 64 == 0 && self.words()[index / 64] == u64::MAX {
+                index = (index + 64 + align - 1) / align * align;
+                continue;
+            }
+
+            match (index..index + count).find(|&i| self.is_used(i)) {
+                Some(used) => index = (used + align) / align * align,
+                None => return Some(index),
+            }
+        }
+        None
+    }
+
+    /// Allocates `count` physically contiguous frames from `zone` for `owner`, aligned to `align`
+    /// frames
+    pub fn allocate_contiguous(
+        &mut self,
+        count: usize,
+        align: usize,
+        zone: Zone,
+        owner: Owner,
+    ) -> Option<PhysFrame<Size4KiB>> {
+        for (start, end) in zone.ranges(self.frames) {
+            if let Some(index) = self.find(start, end, count, align.max(1)) {
+                self.mark_used(index, count);
+                self.usage.charge(owner, count);
+                return Some(PhysFrame::containing_address(PhysAddr::new(
+                    index as u64 * FRAME_SIZE,
+                )));
+            }
+        }
+        None
+    }
 
-        let address_range =
-            usable.map(|u| u.physical_address..(u.physical_address + u.size * 4096));
-        let addresses = address_range.flat_map(|r| r.step_by(4096));
-        addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr as u64)))
+    pub fn allocate_in(&mut self, zone: Zone, owner: Owner) -> Option<PhysFrame<Size4KiB>> {
+        self.allocate_contiguous(1, 1, zone, owner)
     }
 
+    /// Allocates `size` bytes of physically contiguous frames
     pub fn allocate_size(&mut self, size: usize) -> Option<(PhysFrame<Size4KiB>, usize)> {
-        let n = size / 4096;
-        let mut ret_frame = PhysFrame::containing_address(PhysAddr::new(0));
-        for i in 0..n {
-            if let Some(f) = self.allocate_frame() {
-                if i == 0 {
-                    ret_frame = f
-                }
-            } else {
-                return None;
+        let count = (size + FRAME_SIZE as usize - 1) / FRAME_SIZE as usize;
+        self.allocate_contiguous(count, 1, Zone::Normal, Owner::Kernel)
+            .map(|frame| (frame, count))
+    }
+
+    /// Returns frames from `allocate_contiguous` or `allocate_size` that were allocated for
+    /// `owner`
+    pub fn free_contiguous(&mut self, frame: PhysFrame<Size4KiB>, count: usize, owner: Owner) {
+        let index = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        self.mark_free(index, count);
+        self.usage.uncharge(owner, count);
+    }
+
+    /// Counts `size` bytes of memory the allocator never handed out, like the boot image, towards
+    /// `owner`
+    pub fn charge(&mut self, owner: Owner, size: u64) {
+        self.usage.charge(owner, ((size + FRAME_SIZE - 1) / FRAME_SIZE) as usize);
+    }
+
+    pub fn usage(&self) -> &Usage {
+        &self.usage
+    }
+
+    /// Hands every region of `kind` to the allocator once nothing uses it anymore. Returns the
+    /// number of bytes reclaimed.
+    pub fn reclaim(&mut self, kind: RegionKind) -> u64 {
+        for i in 0..self.memory_map.regions().len() {
+            let region = self.memory_map.regions()[i];
+            if region.kind == kind {
+                self.free_region(&region);
             }
         }
-        Some((ret_frame, n))
+        self.memory_map.reclaim(kind)
+    }
+
+    pub fn memory_map(&self) -> &PhysicalMemoryMap {
+        &self.memory_map
+    }
+
+    /// Bytes of free physical memory
+    pub fn free_memory(&self) -> u64 {
+        self.free as u64 * FRAME_SIZE
+    }
+
+    /// Bytes of physical memory the allocator manages
+    pub fn total_memory(&self) -> u64 {
+        self.memory_map.size_of(RegionKind::Usable)
     }
 }
 
-unsafe impl<'a> FrameAllocator<Size4KiB> for PageTableFrameAllocator<'a> {
+unsafe impl FrameAllocator<Size4KiB> for PageTableFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.addresses.next();
-        frame
+        self.allocate_in(Zone::Normal, Owner::Kernel)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for PageTableFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        self.free_contiguous(frame, 1, Owner::Kernel);
+    }
+}
+
+/// The global frame allocator charging every frame to one owner. Mappers allocate page tables
+/// through it too, so they count towards the owner of what is being mapped.
+pub struct Charged {
+    owner: Owner,
+}
+
+pub fn charged(owner: Owner) -> Charged {
+    Charged { owner }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for Charged {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        allocator().lock().allocate_in(Zone::Normal, self.owner)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for Charged {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        allocator().lock().free_contiguous(frame, 1, self.owner);
+    }
+}
+
+/// Adds a reference to a frame that is about to be mapped a second time. `owner` is who the
+/// frame is charged to, unless it was shared before.
+pub fn share_frame(frame: PhysFrame, owner: Owner) {
+    let mut allocator = allocator().lock();
+    allocator
+        .shared
+        .entry(frame.start_address().as_u64())
+        .or_insert(SharedFrame { refs: 0, owner })
+        .refs += 1;
+}
+
+/// Whether more than one address space maps the frame
+pub fn is_shared(frame: PhysFrame) -> bool {
+    let allocator = allocator().lock();
+    allocator
+        .shared
+        .get(&frame.start_address().as_u64())
+        .map_or(false, |shared| shared.refs > 0)
+}
+
+/// Owner a frame that was shared at some point is charged to
+pub fn shared_owner(frame: PhysFrame) -> Option<Owner> {
+    let allocator = allocator().lock();
+    allocator
+        .shared
+        .get(&frame.start_address().as_u64())
+        .map(|shared| shared.owner)
+}
+
+/// Moves the charge of a frame that was shared to `owner`, its last user
+pub fn adopt_frame(frame: PhysFrame, owner: Owner) {
+    let mut allocator = allocator().lock();
+    let address = frame.start_address().as_u64();
+    if let Some(shared) = allocator.shared.get(&address).copied() {
+        if shared.refs == 0 {
+            allocator.shared.remove(&address);
+            allocator.usage.uncharge(shared.owner, 1);
+            allocator.usage.charge(owner, 1);
+        }
+    }
+}
+
+/// Drops a reference to a frame and frees it with the last one, uncharged from whoever it is
+/// charged to. Returns whether it was freed.
+pub fn release_frame(frame: PhysFrame, owner: Owner) -> bool {
+    let mut allocator = allocator().lock();
+    let address = frame.start_address().as_u64();
+    let owner = match allocator.shared.get_mut(&address) {
+        Some(shared) if shared.refs > 0 => {
+            shared.refs -= 1;
+            return false;
+        }
+        Some(shared) => {
+            let owner = shared.owner;
+            allocator.shared.remove(&address);
+            owner
+        }
+        None => owner,
+    };
+    allocator.free_contiguous(frame, 1, owner);
+    true
+}
+
+/// Prints free memory, usage by owner and by process, and the kernel heap
+pub fn print_usage() {
+    let allocator = allocator().lock();
+    let usage = allocator.usage();
+
+    kprintln!(
+        "Memory: {} KiB free of {} KiB",
+        allocator.free_memory() / 1024,
+        allocator.total_memory() / 1024
+    );
+    for (index, name) in Owner::KINDS.iter().enumerate() {
+        kprintln!("  {:<12} {:>10} KiB", name, usage.owners[index] as u64 * FRAME_SIZE / 1024);
+    }
+    for &(id, frames) in usage.processes.iter().filter(|&&(_, n)| n != 0) {
+        kprintln!("  Process {:<4} {:>10} KiB", id, frames as u64 * FRAME_SIZE / 1024);
+    }
+
+    let heap = crate::allocator::heap();
+    kprintln!(
+        "Heap: {} KiB used, {} KiB free of {} KiB",
+        heap.used() / 1024,
+        heap.free() / 1024,
+        heap.size() / 1024
+    );
+    for cache in crate::slab::stats() {
+        kprintln!(
+            "  {:<12} {:>6} objects of {:>4} bytes in {:>4} pages, {} allocations {} frees",
+            cache.name,
+            cache.objects,
+            cache.object_size,
+            cache.pages,
+            cache.allocations,
+            cache.frees
+        );
     }
 }
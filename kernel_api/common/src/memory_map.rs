@@ -0,0 +1,216 @@
+use core::fmt::Debug;
+
+use crate::efi::{self, MemoryType};
+
+/// Upper bound on regions after merging, firmware maps are usually well below this
+const MAX_REGIONS: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    /// Free for the frame allocator. Boot services code is dead after ExitBootServices so it
+    /// counts as usable straight away.
+    Usable,
+    /// Boot services data, the loader stack lives in here until the kernel is done with its
+    /// parameters
+    BootServices,
+    /// Loader image and its pool allocations (boot image, config file)
+    Loader,
+    /// ACPI tables, usable once they have been parsed
+    AcpiReclaim,
+    AcpiNvs,
+    /// Firmware runtime code and data, mapped for runtime services
+    Runtime,
+    Mmio,
+    Reserved,
+}
+
+impl RegionKind {
+    fn from_efi(desc: &efi::MemoryDescriptor) -> RegionKind {
+        if desc.is_runtime() {
+            return match desc.memory_type {
+                MemoryType::MemoryMappedIO | MemoryType::MemoryMappedIOPortSpace => RegionKind::Mmio,
+                _ => RegionKind::Runtime,
+            };
+        }
+
+        match desc.memory_type {
+            MemoryType::Conventional | MemoryType::BootServicesCode => RegionKind::Usable,
+            MemoryType::BootServicesData => RegionKind::BootServices,
+            MemoryType::LoaderCode | MemoryType::LoaderData => RegionKind::Loader,
+            MemoryType::ACPIReclaim => RegionKind::AcpiReclaim,
+            MemoryType::ACPINVS => RegionKind::AcpiNvs,
+            MemoryType::RuntimeServicesCode | MemoryType::RuntimeServicesData => {
+                RegionKind::Runtime
+            }
+            MemoryType::MemoryMappedIO | MemoryType::MemoryMappedIOPortSpace => RegionKind::Mmio,
+            _ => RegionKind::Reserved,
+        }
+    }
+
+    /// Memory that can be handed to the frame allocator once whatever lives in it is done
+    pub fn is_reclaimable(&self) -> bool {
+        matches!(
+            self,
+            RegionKind::BootServices | RegionKind::Loader | RegionKind::AcpiReclaim
+        )
+    }
+}
+
+/// Physical address range `[start, end)`
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: u64,
+    pub end: u64,
+    pub kind: RegionKind,
+}
+
+impl Region {
+    const fn empty() -> Region {
+        Region {
+            start: 0,
+            end: 0,
+            kind: RegionKind::Reserved,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn contains(&self, address: u64) -> bool {
+        address >= self.start && address < self.end
+    }
+}
+
+impl Debug for Region {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:016x}-{:016x} {:?}", self.start, self.end, self.kind)
+    }
+}
+
+/// Sorted, merged physical memory map owned by the kernel. Built from the EFI descriptors once so
+/// nothing downstream has to deal with firmware memory types. Lives in a fixed array so the
+/// loader can build it before there is a heap.
+#[derive(Clone)]
+pub struct PhysicalMemoryMap {
+    regions: [Region; MAX_REGIONS],
+    count: usize,
+}
+
+impl PhysicalMemoryMap {
+    pub fn from_efi(map: efi::MemoryMap<'_>) -> PhysicalMemoryMap {
+        let mut memory_map = PhysicalMemoryMap {
+            regions: [Region::empty(); MAX_REGIONS],
+            count: 0,
+        };
+
+        for desc in map {
+            if desc.physical_address == 0 && desc.virtual_address == 0 && desc.size == 0 {
+                break;
+            }
+
+            let start = desc.physical_address as u64;
+            memory_map.insert(Region {
+                start,
+                end: start + desc.size as u64 * 4096,
+                kind: RegionKind::from_efi(desc),
+            });
+        }
+
+        memory_map.merge();
+        memory_map
+    }
+
+    /// Inserts sorted by start address
+    fn insert(&mut self, region: Region) {
+        assert!(self.count < MAX_REGIONS, "Too many physical memory regions!");
+
+        let index = self.regions[..self.count]
+            .iter()
+            .position(|r| r.start > region.start)
+            .unwrap_or(self.count);
+        self.regions.copy_within(index..self.count, index + 1);
+        self.regions[index] = region;
+        self.count += 1;
+    }
+
+    /// Joins neighbouring regions of the same kind
+    fn merge(&mut self) {
+        if self.count == 0 {
+            return;
+        }
+
+        let mut merged = 0;
+        for i in 1..self.count {
+            let region = self.regions[i];
+            let last = &mut self.regions[merged];
+            if last.kind == region.kind && last.end >= region.start {
+                last.end = last.end.max(region.end);
+            } else {
+                merged += 1;
+                self.regions[merged] = region;
+            }
+        }
+        self.count = merged + 1;
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions[..self.count]
+    }
+
+    pub fn of_kind(&self, kind: RegionKind) -> impl Iterator<Item = &Region> {
+        self.regions().iter().filter(move |r| r.kind == kind)
+    }
+
+    pub fn usable(&self) -> impl Iterator<Item = &Region> {
+        self.of_kind(RegionKind::Usable)
+    }
+
+    pub fn find(&self, address: u64) -> Option<&Region> {
+        self.regions().iter().find(|r| r.contains(address))
+    }
+
+    /// Bytes of memory of a kind
+    pub fn size_of(&self, kind: RegionKind) -> u64 {
+        self.of_kind(kind).map(|r| r.size()).sum()
+    }
+
+    /// End of the highest RAM region, what the physical memory mapping has to cover. Device
+    /// memory above it is mapped with `ioremap`.
+    pub fn highest_address(&self) -> u64 {
+        self.regions()
+            .iter()
+            .filter(|r| !matches!(r.kind, RegionKind::Mmio | RegionKind::Reserved))
+            .last()
+            .map_or(0, |r| r.end)
+    }
+
+    /// Turns every region of `kind` into usable memory. Returns the number of bytes reclaimed.
+    pub fn reclaim(&mut self, kind: RegionKind) -> u64 {
+        assert!(kind.is_reclaimable(), "{:?} memory can't be reclaimed!", kind);
+
+        let size = self.size_of(kind);
+        for region in &mut self.regions[..self.count] {
+            if region.kind == kind {
+                region.kind = RegionKind::Usable;
+            }
+        }
+        self.merge();
+        size
+    }
+
+    pub fn print(&self) {
+        for region in self.regions() {
+            kprintln!("{:?}", region);
+        }
+        kprintln!(
+            "usable: {:x}, reclaimable: {:x}",
+            self.size_of(RegionKind::Usable),
+            self.regions()
+                .iter()
+                .filter(|r| r.kind.is_reclaimable())
+                .map(|r| r.size())
+                .sum::<u64>()
+        );
+    }
+}
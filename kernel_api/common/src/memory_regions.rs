@@ -1,8 +1,74 @@
 
 pub const PAGE_TABLE_OFFSET: u64 = size_tb!(10); // 10 TB
 pub const PROCESS_STACK_ADDRESS: usize = size_gb!(5); // 5GB
+// How far the stack grows down on faults
+pub const PROCESS_STACK_LIMIT: u64 = size_mb!(8);
+pub const PROCESS_TLS_ADDRESS: u64 = size_gb!(6);
+
+// mmap places mappings here, everything above belongs to the kernel
+pub const PROCESS_MMAP_START: u64 = size_gb!(8);
+pub const PROCESS_MMAP_END: u64 = size_tb!(1);
 
 pub const HEAP_START: usize = size_tb!(3);
+// Mapped up front, the heap grows past it on demand
 pub const HEAP_SIZE: usize = size_mb!(10);
+// Virtual range reserved for the heap to grow into
+pub const HEAP_RESERVE: usize = size_gb!(64);
+// How far the heap grows unless the command line says otherwise
+pub const HEAP_LIMIT: usize = size_mb!(512);
+
+// Link address of the kernel image (kernel/link.x)
+pub const KERNEL_CODE: u64 = size_tb!(2);
+
+// Shared object drivers are loaded one after another from here
+pub const MODULE_START: u64 = size_tb!(4);
+pub const MODULE_SIZE: u64 = size_gb!(512);
+
+// Device memory mapped with ioremap
+pub const MMIO_START: u64 = size_tb!(5);
+pub const MMIO_SIZE: u64 = size_gb!(512);
+
+// Windows the bases above are randomized in by the loader
+pub const KERNEL_SLIDE_WINDOW: u64 = size_gb!(512);
+pub const HEAP_SLIDE_WINDOW: usize = size_gb!(512);
+pub const PHYSMAP_SLIDE_WINDOW: u64 = size_tb!(4);
+
+/// Where the loader placed the kernel, heap and physical memory map this boot
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryLayout {
+    /// Added to the kernel's link addresses
+    pub kernel_slide: u64,
+    pub heap_start: usize,
+    pub physmap: u64,
+}
+
+impl MemoryLayout {
+    /// The layout without any randomization
+    pub const fn fixed() -> MemoryLayout {
+        MemoryLayout {
+            kernel_slide: 0,
+            heap_start: HEAP_START,
+            physmap: PAGE_TABLE_OFFSET,
+        }
+    }
+}
+
+static mut LAYOUT: MemoryLayout = MemoryLayout::fixed();
+
+pub fn layout() -> MemoryLayout {
+    unsafe { LAYOUT }
+}
+
+/// Must be set before the heap or any page table through the physical memory map is touched
+pub unsafe fn set_layout(layout: MemoryLayout) {
+    LAYOUT = layout;
+}
+
+pub fn heap_start() -> usize {
+    layout().heap_start
+}
 
-pub const KERNEL_CODE: u64 = size_tb!(2);
\ No newline at end of file
+/// Virtual address all of physical memory is mapped at in the kernel address space
+pub fn physmap_base() -> u64 {
+    layout().physmap
+}
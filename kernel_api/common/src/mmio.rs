@@ -0,0 +1,159 @@
+use core::marker::PhantomData;
+
+use x86_64::{
+    instructions::tlb,
+    registers::model_specific::Msr,
+    structures::paging::{
+        mapper::MapToError, Mapper, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    mem,
+    memory_regions::{self, MMIO_SIZE, MMIO_START},
+    slab,
+};
+
+const IA32_PAT: u32 = 0x277;
+
+/* PAT memory types */
+const PAT_UC: u64 = 0x00;
+const PAT_WC: u64 = 0x01;
+const PAT_WT: u64 = 0x04;
+const PAT_WB: u64 = 0x06;
+
+/// Caching of a device mapping. The PAT is programmed so the PWT and PCD bits of a 4KiB entry
+/// pick the type without the PAT bit, which the mapper can't set on small pages:
+/// none is write-back, PWT write-combining, PCD write-through and both uncached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    Uncached,
+    WriteCombining,
+    WriteThrough,
+}
+
+impl CacheMode {
+    pub fn flags(&self) -> PageTableFlags {
+        match self {
+            CacheMode::Uncached => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+            CacheMode::WriteCombining => PageTableFlags::WRITE_THROUGH,
+            CacheMode::WriteThrough => PageTableFlags::NO_CACHE,
+        }
+    }
+}
+
+/// Programs the PAT for `CacheMode`, has to run on every CPU before device memory is mapped
+pub fn init_pat() {
+    let entries = PAT_WB | PAT_WC << 8 | PAT_WT << 16 | PAT_UC << 24;
+    unsafe {
+        Msr::new(IA32_PAT).write(entries | entries << 32);
+    }
+    tlb::flush_all();
+}
+
+/// Next free address in the MMIO window. Mappings are never reused, the window is large enough
+/// for the devices of one boot.
+static mut NEXT_MMIO: u64 = MMIO_START;
+
+/// Typed handle to device memory mapped with `ioremap`, unmapped on drop
+pub struct Mmio<T> {
+    address: u64,
+    size: usize,
+    phantom: PhantomData<*mut T>,
+}
+
+unsafe impl<T> Send for Mmio<T> {}
+
+impl<T> Mmio<T> {
+    pub fn as_ptr(&self) -> *mut T {
+        self.address as *mut T
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Volatile read of a `U` at a byte offset, for register blocks that aren't described by `T`
+    pub fn read_at<U: Copy>(&self, offset: usize) -> U {
+        assert!(offset + core::mem::size_of::<U>() <= self.size, "MMIO read out of bounds!");
+        unsafe { core::ptr::read_volatile((self.address + offset as u64) as *const U) }
+    }
+
+    pub fn write_at<U: Copy>(&mut self, offset: usize, value: U) {
+        assert!(offset + core::mem::size_of::<U>() <= self.size, "MMIO write out of bounds!");
+        unsafe { core::ptr::write_volatile((self.address + offset as u64) as *mut U, value) }
+    }
+}
+
+impl<T: Copy> Mmio<T> {
+    pub fn read(&self) -> T {
+        self.read_at(0)
+    }
+
+    pub fn write(&mut self, value: T) {
+        self.write_at(0, value)
+    }
+}
+
+impl<T> Drop for Mmio<T> {
+    fn drop(&mut self) {
+        let mut mapper = mem::active_offset_page_table(memory_regions::physmap_base());
+        for page in pages(self.address, self.size) {
+            if let Ok((_, flush)) = mapper.unmap(page) {
+                flush.flush();
+            }
+        }
+    }
+}
+
+/// Pages covering `[address, address + size)`
+fn pages(address: u64, size: usize) -> impl Iterator<Item = Page<Size4KiB>> {
+    Page::range_inclusive(
+        Page::containing_address(VirtAddr::new(address)),
+        Page::containing_address(VirtAddr::new(address + size.max(1) as u64 - 1)),
+    )
+}
+
+/// Maps `size` bytes of device memory at `phys` into the kernel MMIO window with `mode` caching
+pub fn ioremap<T>(
+    phys: PhysAddr,
+    size: usize,
+    mode: CacheMode,
+) -> Result<Mmio<T>, MapToError<Size4KiB>> {
+    let offset = phys.as_u64() % Size4KiB::SIZE;
+    let mapped_size = (offset + size as u64 + Size4KiB::SIZE - 1) & !(Size4KiB::SIZE - 1);
+
+    let base = unsafe {
+        if NEXT_MMIO + mapped_size > MMIO_START + MMIO_SIZE {
+            return Err(MapToError::FrameAllocationFailed);
+        }
+        let base = NEXT_MMIO;
+        NEXT_MMIO += mapped_size;
+        base
+    };
+
+    let mut mapper = mem::active_offset_page_table(memory_regions::physmap_base());
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | mode.flags();
+    if mem::nx_enabled() {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+
+    let frames = PhysFrame::<Size4KiB>::range(
+        PhysFrame::containing_address(phys),
+        PhysFrame::containing_address(phys + mapped_size),
+    );
+    for (frame, page) in frames.zip(pages(base, mapped_size as usize)) {
+        unsafe {
+            mapper
+                .map_to(page, frame, flags, &mut slab::page_tables())?
+                .flush();
+        }
+    }
+
+    Ok(Mmio {
+        address: base + offset,
+        size,
+        phantom: PhantomData,
+    })
+}
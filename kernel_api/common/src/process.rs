@@ -1,23 +1,37 @@
 use core::{arch::asm, sync::atomic::AtomicU32};
 
-use alloc::boxed::Box;
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
 use x86_64::{
+    instructions::tlb,
     structures::paging::{
-        page::PageRangeInclusive, FrameAllocator, Mapper, OffsetPageTable, Page, PageTable,
-        PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB, mapper::MapToError,
+        mapper::{MapToError, MappedFrame, TranslateResult},
+        page::{PageRange, PageRangeInclusive},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable,
+        PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB, Translate,
     },
     PhysAddr, VirtAddr,
 };
 
 use crate::{
-    efi,
     elf::{self, SegmentType},
-    mem, memory_regions::{self, PROCESS_STACK_ADDRESS},
+    mem::{self, Owner},
+    memory_map::RegionKind,
+    memory_regions::{
+        self, PROCESS_MMAP_END, PROCESS_MMAP_START, PROCESS_STACK_ADDRESS, PROCESS_STACK_LIMIT,
+    },
+    vma::{
+        self, Backing, Protection, SharedFrames, Vma, VmError, Vmas, COPY_ON_WRITE, MAP_DEVICE,
+        MAP_FIXED, MAP_SHARED, PAGE_SIZE,
+    },
 };
 
 
+/* The syscall entry stub in the kernel refers to these by name */
+#[no_mangle]
 pub static mut SYSCALL_SP: u64 = 0x0;
+#[no_mangle]
 pub static mut SYSCALL_USP: u64 = 0x0;
+#[no_mangle]
 pub static mut SYSCALL_UMAP: u64 = 0x0;
 
 #[inline(always)]
@@ -29,12 +43,21 @@ pub type ProcessId = u32;
 
 static IDINDEX: AtomicU32 = AtomicU32::new(0);
 
+/// Id the next loaded process gets, to charge its memory to it while it is loaded
+pub fn next_id() -> ProcessId {
+    IDINDEX.load(core::sync::atomic::Ordering::SeqCst)
+}
+
 #[derive(Debug)]
 pub struct Process {
     pub id: ProcessId,
     pub address_space: Box<PageTable>,
     pub stack_base: *mut u64,
     pub entry: fn(),
+    /// Thread pointer for `IA32_FS_BASE`, 0 without a TLS segment
+    pub fs_base: u64,
+    /// Everything mapped in the user half
+    pub vmas: Vmas,
 }
 
 impl Process {
@@ -49,41 +72,34 @@ impl Process {
 
     pub fn kernel_from_elf(
         elf: &elf::ElfFile<'_>,
+        slide: u64,
         kernel_stack_start: u64,
         kernel_stack_end: u64,
         mem: usize,
         current_mapper: &mut impl Mapper<Size4KiB>,
-        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+        frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
     ) -> Process {
         let mut new_page_table = Box::new(PageTable::new());
         let mut mapper = unsafe { OffsetPageTable::new(&mut new_page_table, VirtAddr::new(0)) };
 
-        let phys_mem_start = PhysFrame::containing_address(PhysAddr::zero());
-        let phys_mem_end = PhysFrame::containing_address(PhysAddr::new(mem as _));
-        let phys_frames = PhysFrame::<Size1GiB>::range_inclusive(phys_mem_start, phys_mem_end);
+        // Whole gigabytes, so it can use 1GiB pages where the processor has them
+        let phys_mem_size = (mem as u64 / Size1GiB::SIZE + 1) * Size1GiB::SIZE;
+        mem::map_range(
+            &mut mapper,
+            PhysAddr::zero(),
+            VirtAddr::new(memory_regions::physmap_base()),
+            phys_mem_size,
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+            frame_allocator,
+        )
+        .expect("Unable to map physical memory!");
 
-        unsafe {
-            for frame in phys_frames {
-                kprintln!("Frame {:x?}", frame);
-                let page = Page::containing_address(
-                    VirtAddr::new(memory_regions::PAGE_TABLE_OFFSET) + frame.start_address().as_u64(),
-                );
-                mapper
-                    .map_to(
-                        page,
-                        frame,
-                        PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
-                        frame_allocator,
-                    )
-                    .expect("Unable to identity map!")
-                    .ignore();
-            }
-        }
-
-        // Setup stack
+        // Setup stack, the kernel runs on it so SMAP would fault on a user page
         let stack_pages = Process::get_stack();
-        let stack_flags =
-            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        let mut stack_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        if mem::nx_enabled() {
+            stack_flags |= PageTableFlags::NO_EXECUTE;
+        }
 
         for page in stack_pages {
             kprintln!("Process Stack {:?}", page);
@@ -98,23 +114,8 @@ impl Process {
             };
         }
 
-        /* Map kernel crap for syscalls and interrupts */
-        let kernel_code_descriptor = unsafe {
-            efi::DESCRIPTORS
-                .iter()
-                .find(|d| matches!(d.memory_type, efi::MemoryType::LoaderCode))
-                .expect("Unable to find loader code!")
-        };
-        let kernel_code_start = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(
-            kernel_code_descriptor.physical_address as u64,
-        ));
-        let kernel_code_end = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(
-            kernel_code_descriptor.physical_address as u64
-                + kernel_code_descriptor.size as u64 * 4096,
-        ));
-        let kernel_code_frames =
-            PhysFrame::<Size4KiB>::range_inclusive(kernel_code_start, kernel_code_end);
-
+        /* The loader stack holds the kernel parameters, so it stays mapped until the kernel
+         * has copied what it needs. Loader code is never mapped in here. */
         let kernel_stack_end =
             PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(kernel_stack_end));
         let kernel_stack_start =
@@ -122,123 +123,68 @@ impl Process {
         let kernel_stack_frames =
             PhysFrame::<Size4KiB>::range_inclusive(kernel_stack_end, kernel_stack_start);
 
-        unsafe {
-            for frame in kernel_code_frames {
-                mapper
-                    .identity_map(
-                        frame,
-                        // For now the test process is in kernel code so user accessable flag is set
-                        PageTableFlags::USER_ACCESSIBLE
-                            | PageTableFlags::PRESENT
-                            | PageTableFlags::WRITABLE,
-                        frame_allocator,
-                    )
-                    .expect("Unable to identity map!");
-            }
+        let mut data_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        if mem::nx_enabled() {
+            data_flags |= PageTableFlags::NO_EXECUTE;
+        }
 
+        // The local APIC is mapped uncached by the kernel with `mmio::ioremap`
+        unsafe {
             for frame in kernel_stack_frames {
                 mapper
-                    .identity_map(
-                        frame,
-                        PageTableFlags::PRESENT
-                            | PageTableFlags::WRITABLE
-                            | PageTableFlags::WRITABLE,
-                        frame_allocator,
-                    )
+                    .identity_map(frame, data_flags, frame_allocator)
                     .expect("Unable to identity map!");
             }
-
-            /* APIC register mapping for kernel */
-            mapper
-                .identity_map(
-                    PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(0xFEE00000)),
-                    PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
-                    frame_allocator,
-                )
-                .expect("Unable to map apic regs for process!");
         }
 
         let header = elf.header();
+        assert!(
+            header.entry >= memory_regions::KERNEL_CODE,
+            "Kernel entry {:x} is not in the higher half!",
+            { header.entry }
+        );
+
+        let mut last_page = None;
         for pheader in elf.progam_headers() {
-            match pheader.segement_type {
+            match pheader.segment_type() {
                 SegmentType::Load => {
-                    // Pages of segment virtual address
-                    let pg_start = Page::<Size4KiB>::containing_address(VirtAddr::new(
-                        pheader.virtual_address,
-                    ));
-                    let pg_end = Page::<Size4KiB>::containing_address(VirtAddr::new(
-                        pheader.virtual_address + pheader.segment_mem_size,
-                    ));
+                    assert!(
+                        pheader.virtual_address >= memory_regions::KERNEL_CODE,
+                        "Kernel segment {:x} is not linked in the higher half!",
+                        { pheader.virtual_address }
+                    );
 
-                    let pages = Page::<Size4KiB>::range_inclusive(pg_start, pg_end);
-
-                    /* Segment Data */
-                    let data = elf.segment(pheader);
-
-                    let mut file_size = pheader.segment_file_size;
-
-                    if let Some(data) = data {
-                        /* Map virtual pages, allocate physical frames and copy the segment data
-                         * from elf file to those frames */
-                        for page in pages {
-                            let frame = frame_allocator
-                                .allocate_frame()
-                                .expect("Unable to allocate physical frame for elf process!");
-
-                            let frame_addr = unsafe {
-                                // Identity map frame in current address space for copying
-                                current_mapper.identity_map(
-                                    frame,
-                                    PageTableFlags::WRITABLE | PageTableFlags::PRESENT,
-                                    frame_allocator,
-                                );
-
-                                core::slice::from_raw_parts_mut(
-                                    ((frame.start_address().as_u64()
-                                        + (pheader.virtual_address
-                                            - (pheader.virtual_address & !0xFFF))
-                                            as u64)
-                                        as *mut u8),
-                                    file_size.min(4096) as usize,
-                                )
-                            };
-
-                            /* Copy segment data */
-                            let offset = (pheader.segment_file_size - file_size) as usize;
-                            frame_addr.copy_from_slice(
-                                &data[offset..offset + file_size.min(4096) as usize],
-                            );
-
-                            /* Map frames in processes new address space*/
-                            unsafe {
-                                mapper.map_to(
-                                    page,
-                                    frame,
-                                    PageTableFlags::WRITABLE
-                                        | PageTableFlags::USER_ACCESSIBLE
-                                        | PageTableFlags::PRESENT,
-                                    frame_allocator,
-                                );
-                            }
-
-                            if file_size > 4096 {
-                                file_size -= 4096;
-                            }
-                        }
-                    }
+                    last_page = load_segment(
+                        elf,
+                        pheader,
+                        slide,
+                        segment_flags(pheader),
+                        last_page,
+                        &mut mapper,
+                        current_mapper,
+                        frame_allocator,
+                    )
+                    .expect("Unable to load kernel segment!");
                 }
                 _ => (),
             }
         }
 
-        let id = IDINDEX.load(core::sync::atomic::Ordering::SeqCst);
-        IDINDEX.store(id, core::sync::atomic::Ordering::SeqCst);
+        if header.is_position_independent() {
+            let count =
+                relocate(elf, slide, &mapper, |_| None).expect("Unable to relocate kernel!");
+            kprintln!("Applied {} kernel relocations", count);
+        }
+
+        let id = IDINDEX.fetch_add(1, core::sync::atomic::Ordering::SeqCst);
 
         Process {
             id,
             address_space: new_page_table,
             stack_base: PROCESS_STACK_ADDRESS as *mut u64,
-            entry: unsafe { core::mem::transmute(header.entry as *const ()) },
+            entry: unsafe { core::mem::transmute((header.entry + slide) as *const ()) },
+            fs_base: 0,
+            vmas: Vmas::new(),
         }
     }
 
@@ -249,40 +195,45 @@ impl Process {
         kernel_stack_end: u64,
         mem: usize,
         current_mapper: &mut impl Mapper<Size4KiB>,
-        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-    ) -> Process {
+        frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+    ) -> Result<Process, elf::LoadError> {
         let mut new_page_table = Box::new(PageTable::new());
         #[cfg(feature = "bootloader")]
         let mut mapper = unsafe { OffsetPageTable::new(&mut new_page_table, VirtAddr::new(0)) };
         #[cfg(feature = "kernel")]
-        let mut mapper = unsafe { OffsetPageTable::new(&mut new_page_table, VirtAddr::new(memory_regions::PAGE_TABLE_OFFSET)) };
+        let mut mapper = unsafe { OffsetPageTable::new(&mut new_page_table, VirtAddr::new(memory_regions::physmap_base())) };
 
-        let phys_mem_start = PhysFrame::containing_address(PhysAddr::zero());
-        let phys_mem_end = PhysFrame::containing_address(PhysAddr::new(mem as _));
-        let phys_frames = PhysFrame::<Size1GiB>::range_inclusive(phys_mem_start, phys_mem_end);
-
-        unsafe {
-            for frame in phys_frames {
-                kprintln!("Frame {:x?}", frame);
-                let page = Page::containing_address(
-                    VirtAddr::new(memory_regions::PAGE_TABLE_OFFSET) + frame.start_address().as_u64(),
-                );
-                mapper
-                    .map_to(
-                        page,
-                        frame,
-                        PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
-                        frame_allocator,
-                    )
-                    .expect("Unable to identity map!")
-                    .ignore();
-            }
-        }
+        // Whole gigabytes, so it can use 1GiB pages where the processor has them
+        let phys_mem_size = (mem as u64 / Size1GiB::SIZE + 1) * Size1GiB::SIZE;
+        mem::map_range(
+            &mut mapper,
+            PhysAddr::zero(),
+            VirtAddr::new(memory_regions::physmap_base()),
+            phys_mem_size,
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+            frame_allocator,
+        )
+        .expect("Unable to map physical memory!");
 
         // Setup stack
         let stack_pages = Process::get_stack();
-        let stack_flags =
+        let mut stack_flags =
             PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        if mem::nx_enabled() {
+            stack_flags |= PageTableFlags::NO_EXECUTE;
+        }
+
+        let mut vmas = Vmas::new();
+        vmas.insert(Vma {
+            grows_down: true,
+            ..Vma::new(
+                stack_pages.start.start_address().as_u64(),
+                stack_pages.end.start_address().as_u64() + PAGE_SIZE,
+                Protection::READ | Protection::WRITE,
+                Backing::Anonymous,
+            )
+        })
+        .expect("Unable to record process stack!");
 
         for page in stack_pages {
             kprintln!("Process Stack {:?}", page);
@@ -297,14 +248,6 @@ impl Process {
             };
         }
 
-        /* Map kernel crap for syscalls and interrupts */
-        // let kernel_code_start =
-        //     PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(kernel_code_start));
-        // let kernel_code_end =
-        //     PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(kernel_code_end));
-        // let kernel_code_frames =
-        //     PhysFrame::<Size4KiB>::range_inclusive(kernel_code_start, kernel_code_end);
-
         let kernel_stack_end =
             PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(kernel_stack_end));
         let kernel_stack_start =
@@ -312,54 +255,31 @@ impl Process {
         let kernel_stack_frames =
             PhysFrame::<Size4KiB>::range_inclusive(kernel_stack_end, kernel_stack_start);
 
+        /* Everything the kernel maps here for syscalls and interrupts is supervisor-only */
         unsafe {
-            // for frame in kernel_code_frames {
-            //     mapper
-            //         .identity_map(
-            //             frame,
-            //             // For now the test process is in kernel code so user accessable flag is set
-            //             PageTableFlags::USER_ACCESSIBLE
-            //                 | PageTableFlags::PRESENT
-            //                 | PageTableFlags::WRITABLE,
-            //             frame_allocator,
-            //         )
-            //         .expect("Unable to identity map!");
-            // }
-
             for frame in kernel_stack_frames {
                 mapper
                     .identity_map(
                         frame,
-                        PageTableFlags::PRESENT
-                            | PageTableFlags::WRITABLE
-                            | PageTableFlags::WRITABLE,
+                        PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
                         frame_allocator,
                     )
                     .expect("Unable to identity map!");
             }
-
-            /* APIC register mapping for kernel */
-            // mapper
-            //     .identity_map(
-            //         PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(0xFEE00000)),
-            //         PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
-            //         frame_allocator,
-            //     )
-            //     .expect("Unable to map apic regs for process!");
         }
 
         // Map kernel data
-        let header = kernel.header();
+        let kernel_slide = memory_regions::layout().kernel_slide;
         for pheader in kernel.progam_headers() {
-            match pheader.segement_type {
-                SegmentType::Load => {
+            match pheader.segment_type() {
+                SegmentType::Load if pheader.segment_mem_size > 0 => {
                     // Pages of segment virtual address
-                    let pg_start = Page::<Size4KiB>::containing_address(VirtAddr::new(
-                        pheader.virtual_address,
-                    ));
-                    let pg_end = Page::<Size4KiB>::containing_address(VirtAddr::new(
-                        pheader.virtual_address + pheader.segment_mem_size,
-                    ));
+                    let segment_start = pheader.virtual_address + kernel_slide;
+                    let segment_end = segment_start + pheader.segment_mem_size;
+                    let pg_start =
+                        Page::<Size4KiB>::containing_address(VirtAddr::new(segment_start));
+                    let pg_end =
+                        Page::<Size4KiB>::containing_address(VirtAddr::new(segment_end - 1));
 
                     let pages = Page::<Size4KiB>::range_inclusive(pg_start, pg_end);
 
@@ -372,7 +292,7 @@ impl Process {
                                     .map_to(
                                         page,
                                         frame,
-                                        PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+                                        segment_flags(pheader),
                                         frame_allocator,
                                     )
                                     .unwrap();
@@ -386,94 +306,910 @@ impl Process {
         }
 
         let header = elf.header();
+        /* Dropping the process on error frees whatever got mapped, areas are recorded first */
+        let mut process = Process {
+            id: IDINDEX.fetch_add(1, core::sync::atomic::Ordering::SeqCst),
+            address_space: new_page_table,
+            stack_base: PROCESS_STACK_ADDRESS as *mut u64,
+            entry: unsafe { core::mem::transmute(header.entry as *const ()) },
+            fs_base: 0,
+            vmas,
+        };
+        let mut mapper = unsafe {
+            OffsetPageTable::new(
+                process.address_space.as_mut(),
+                VirtAddr::new(physical_offset()),
+            )
+        };
+
+        let mut last_page = None;
         for pheader in elf.progam_headers() {
-            match pheader.segement_type {
+            match pheader.segment_type() {
                 SegmentType::Load => {
-                    // Pages of segment virtual address
-                    let pg_start = Page::<Size4KiB>::containing_address(VirtAddr::new(
-                        pheader.virtual_address,
-                    ));
-                    let pg_end = Page::<Size4KiB>::containing_address(VirtAddr::new(
-                        pheader.virtual_address + pheader.segment_mem_size,
-                    ));
-
-                    let pages = Page::<Size4KiB>::range_inclusive(pg_start, pg_end);
+                    let end = pheader
+                        .virtual_address
+                        .checked_add(pheader.segment_mem_size)
+                        .filter(|&end| {
+                            pheader.virtual_address >= PAGE_SIZE && end <= PROCESS_MMAP_END
+                        })
+                        .ok_or(elf::LoadError::BadAddress(pheader.virtual_address))?;
 
-                    /* Segment Data */
-                    let data = elf.segment(pheader);
-
-                    let mut file_size = pheader.segment_file_size;
-
-                    if let Some(data) = data {
-                        /* Map virtual pages, allocate physical frames and copy the segment data
-                         * from elf file to those frames */
-                        for page in pages {
-                            let frame = frame_allocator
-                                .allocate_frame()
-                                .expect("Unable to allocate physical frame for elf process!");
-
-                            let frame_addr = unsafe {
-                                // Identity map frame in current address space for copying
-                                current_mapper.identity_map(
-                                    frame,
-                                    PageTableFlags::WRITABLE | PageTableFlags::PRESENT,
-                                    frame_allocator,
-                                );
-
-                                core::slice::from_raw_parts_mut(
-                                    ((frame.start_address().as_u64()
-                                        + (pheader.virtual_address
-                                            - (pheader.virtual_address & !0xFFF))
-                                            as u64)
-                                        as *mut u8),
-                                    file_size.min(4096) as usize,
-                                )
-                            };
-
-                            /* Copy segment data */
-                            let offset = (pheader.segment_file_size - file_size) as usize;
-                            frame_addr.copy_from_slice(
-                                &data[offset..offset + file_size.min(4096) as usize],
-                            );
-
-                            /* Map frames in processes new address space*/
-                            match unsafe {
-                                mapper.map_to(
-                                    page,
-                                    frame,
-                                    PageTableFlags::WRITABLE
-                                        | PageTableFlags::USER_ACCESSIBLE
-                                        | PageTableFlags::PRESENT,
-                                    frame_allocator,
-                                )
-                            } {
-                                Ok(_) => (),
-                                Err(MapToError::PageAlreadyMapped(frame)) => kprintln!("Frame already mapped {:x}", frame.start_address().as_u64()),
-                                Err(e) => panic!("Unable to map frame! {:?}", e)
-                            }
-
-                            if file_size > 4096 {
-                                file_size -= 4096;
-                            }
-                        }
+                    /* A page shared with the previous segment stays in its area */
+                    let mut start = vma::page_align_down(pheader.virtual_address);
+                    let end = vma::page_align_up(end);
+                    while start < end && process.vmas.overlaps(start, end) {
+                        start += PAGE_SIZE;
                     }
+                    if start < end {
+                        let protection = segment_protection(pheader);
+                        process
+                            .vmas
+                            .insert(Vma::new(start, end, protection, Backing::Anonymous))
+                            .map_err(|_| elf::LoadError::Overlap(start))?;
+                    }
+
+                    last_page = load_segment(
+                        elf,
+                        pheader,
+                        0,
+                        segment_flags(pheader) | PageTableFlags::USER_ACCESSIBLE,
+                        last_page,
+                        &mut mapper,
+                        current_mapper,
+                        frame_allocator,
+                    )?;
                 }
                 _ => (),
             }
         }
 
-        let id = IDINDEX.load(core::sync::atomic::Ordering::SeqCst);
-        IDINDEX.store(id, core::sync::atomic::Ordering::SeqCst);
+        if let Some(tls) = elf.tls() {
+            let thread_pointer = tls_thread_pointer(tls)?;
+            process
+                .vmas
+                .insert(Vma::new(
+                    memory_regions::PROCESS_TLS_ADDRESS,
+                    vma::page_align_up(thread_pointer + TCB_SIZE),
+                    Protection::READ | Protection::WRITE,
+                    Backing::Anonymous,
+                ))
+                .map_err(|_| elf::LoadError::Overlap(memory_regions::PROCESS_TLS_ADDRESS))?;
+            load_tls(elf, tls, thread_pointer, &mut mapper, current_mapper, frame_allocator)?;
+            process.fs_base = thread_pointer;
+        }
 
-        Process {
+        Ok(process)
+    }
+
+    pub fn get_pt(&mut self) -> OffsetPageTable {
+        unsafe {
+            OffsetPageTable::new(
+                self.address_space.as_mut(),
+                VirtAddr::new(physical_offset()),
+            )
+        }
+    }
+
+    /// Maps `size` bytes at `address`, or wherever there is room in the mmap window unless
+    /// `MAP_FIXED` is set. Device mappings map the physical address `offset`. Returns the start
+    /// of the mapping. A fixed mapping only replaces what was there once everything else worked.
+    pub fn mmap(
+        &mut self,
+        address: u64,
+        size: u64,
+        protection: Protection,
+        flags: u64,
+        offset: u64,
+    ) -> Result<u64, VmError> {
+        if size == 0
+            || size > PROCESS_MMAP_END
+            || flags & !(MAP_SHARED | MAP_FIXED | MAP_DEVICE) != 0
+        {
+            return Err(VmError::InvalidArgument);
+        }
+        let size = vma::page_align_up(size);
+        let fixed = flags & MAP_FIXED != 0;
+
+        let start = if fixed {
+            user_range(address, size)?;
+            address
+        } else {
+            self.vmas.find_free(size).ok_or(VmError::OutOfMemory)?
+        };
+
+        let owner = Owner::Process(self.id);
+        let backing = if flags & MAP_DEVICE != 0 {
+            if offset % PAGE_SIZE != 0 {
+                return Err(VmError::InvalidArgument);
+            }
+            let end = offset.checked_add(size).ok_or(VmError::InvalidArgument)?;
+            // Only MMIO or holes in the map, never RAM, firmware or loader memory
+            let device = mem::allocator()
+                .lock()
+                .memory_map()
+                .regions()
+                .iter()
+                .filter(|r| r.start < end && r.end > offset)
+                .all(|r| r.kind == RegionKind::Mmio);
+            if !device {
+                return Err(VmError::Forbidden);
+            }
+            Backing::Device(PhysAddr::new(offset))
+        } else if flags & MAP_SHARED != 0 {
+            // Dropping a partly filled set frees what was allocated
+            let mut shared = SharedFrames::new(Vec::new(), owner);
+            for _ in 0..size / PAGE_SIZE {
+                let frame = mem::charged(owner)
+                    .allocate_frame()
+                    .ok_or(VmError::OutOfMemory)?;
+                zero_frame(frame);
+                shared.frames.push(frame);
+            }
+            Backing::Shared(Arc::new(shared))
+        } else {
+            Backing::Anonymous
+        };
+
+        if fixed {
+            self.munmap(start, size)?;
+        }
+        let vma = Vma::new(start, start + size, protection, backing);
+        if let Err(e) = self.populate(&vma).and_then(|_| self.vmas.insert(vma.clone())) {
+            self.unmap_pages(&vma);
+            return Err(e);
+        }
+        Ok(start)
+    }
+
+    /// Unmaps everything in the range, freeing the frames private mappings own
+    pub fn munmap(&mut self, address: u64, size: u64) -> Result<(), VmError> {
+        let end = user_range(address, size)?;
+        for vma in self.vmas.remove(address, end) {
+            self.unmap_pages(&vma);
+        }
+        Ok(())
+    }
+
+    /// Changes the protection of the range, which has to be mapped without holes
+    pub fn mprotect(
+        &mut self,
+        address: u64,
+        size: u64,
+        protection: Protection,
+    ) -> Result<(), VmError> {
+        let end = user_range(address, size)?;
+        let changed = self.vmas.protect(address, end, protection)?;
+
+        let mut mapper = self.get_pt();
+        for vma in changed {
+            for page in vma_pages(&vma) {
+                let mut flags = vma.page_flags();
+                // Copy-on-write pages stay read-only until they are copied
+                if let TranslateResult::Mapped { flags: old, .. } =
+                    mapper.translate(page.start_address())
+                {
+                    if old.contains(COPY_ON_WRITE) {
+                        flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                    }
+                }
+                if let Ok(flush) = unsafe { mapper.update_flags(page, flags) } {
+                    flush.flush();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Clones the address space into a new process. Private memory is shared copy-on-write, both
+    /// sides map it read-only with `COPY_ON_WRITE` until a write fault copies the page. Shared and
+    /// device memory stays shared and the kernel half shares its page tables. If this fails the
+    /// child is dropped, which undoes what was cloned so far.
+    pub fn fork(&mut self) -> Result<Process, VmError> {
+        let id = IDINDEX.fetch_add(1, core::sync::atomic::Ordering::SeqCst);
+        let owner = Owner::Process(id);
+        let parent = Owner::Process(self.id);
+        let user_entries = (PROCESS_MMAP_END >> 39) as usize;
+
+        let mut process = Process {
             id,
-            address_space: new_page_table,
-            stack_base: PROCESS_STACK_ADDRESS as *mut u64,
-            entry: unsafe { core::mem::transmute(header.entry as *const ()) },
+            address_space: Box::new(PageTable::new()),
+            stack_base: self.stack_base,
+            entry: self.entry,
+            fs_base: self.fs_base,
+            vmas: self.vmas.clone(),
+        };
+        let mut child = unsafe {
+            OffsetPageTable::new(&mut process.address_space, VirtAddr::new(physical_offset()))
+        };
+
+        for i4 in 0..512 {
+            let entry = &self.address_space[i4];
+            if entry.is_unused() {
+                continue;
+            }
+            if i4 >= user_entries {
+                // The tables are freed by whichever address space is torn down last
+                mem::share_frame(PhysFrame::containing_address(entry.addr()), parent);
+                child.level_4_table()[i4] = entry.clone();
+                continue;
+            }
+
+            let l3 = table(entry.addr());
+            for i3 in 0..512 {
+                if !l3[i3].flags().contains(PageTableFlags::PRESENT) {
+                    continue;
+                }
+                let address = (i4 << 39 | i3 << 30) as u64;
+                if l3[i3].flags().contains(PageTableFlags::HUGE_PAGE) {
+                    clone_mapping::<Size1GiB>(&mut child, address, l3[i3].addr(), l3[i3].flags(), owner)?;
+                    continue;
+                }
+
+                let l2 = table(l3[i3].addr());
+                for i2 in 0..512 {
+                    if !l2[i2].flags().contains(PageTableFlags::PRESENT) {
+                        continue;
+                    }
+                    let address = address | (i2 << 21) as u64;
+                    if l2[i2].flags().contains(PageTableFlags::HUGE_PAGE) {
+                        clone_mapping::<Size2MiB>(&mut child, address, l2[i2].addr(), l2[i2].flags(), owner)?;
+                        continue;
+                    }
+
+                    let l1 = table(l2[i2].addr());
+                    for i1 in 0..512 {
+                        let mut flags = l1[i1].flags();
+                        if !flags.contains(PageTableFlags::PRESENT) {
+                            continue;
+                        }
+                        let address = address | (i1 << 12) as u64;
+
+                        /* Private frames get a second reference and turn read-only copy-on-write
+                         * in both address spaces, even read-only ones so mprotect can't make them
+                         * writable. Mappings outside any area are kernel ones. */
+                        let private = self.vmas.find(address).map_or(false, |vma| vma.owns_frames());
+                        if private {
+                            flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                        }
+                        clone_mapping::<Size4KiB>(&mut child, address, l1[i1].addr(), flags, owner)?;
+                        if private {
+                            mem::share_frame(PhysFrame::containing_address(l1[i1].addr()), parent);
+                            l1[i1].set_flags(flags);
+                        }
+                    }
+                }
+            }
         }
+        tlb::flush_all();
+
+        Ok(process)
     }
 
-    pub fn get_pt(&mut self) -> OffsetPageTable {
-        unsafe { OffsetPageTable::new(self.address_space.as_mut(), VirtAddr::new(0)) }
+    /// Gives the process its own copy of a copy-on-write page. The last user of a frame just
+    /// makes it writable again.
+    fn copy_on_write(
+        &mut self,
+        vma: &Vma,
+        page: Page<Size4KiB>,
+        frame: PhysFrame,
+    ) -> Result<(), VmError> {
+        let owner = Owner::Process(self.id);
+        let mut mapper = self.get_pt();
+
+        if !mem::is_shared(frame) {
+            mem::adopt_frame(frame, owner);
+            unsafe {
+                mapper
+                    .update_flags(page, vma.page_flags())
+                    .map_err(|_| VmError::NotMapped)?
+                    .flush();
+            }
+            return Ok(());
+        }
+
+        let copy = mem::charged(owner)
+            .allocate_frame()
+            .ok_or(VmError::OutOfMemory)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                (physical_offset() + frame.start_address().as_u64()) as *const u8,
+                (physical_offset() + copy.start_address().as_u64()) as *mut u8,
+                PAGE_SIZE as usize,
+            );
+            mapper
+                .unmap(page)
+                .map_err(|_| VmError::NotMapped)?
+                .1
+                .flush();
+            mapper
+                .map_to(page, copy, vma.page_flags(), &mut mem::charged(owner))
+                .map_err(|_| VmError::OutOfMemory)?
+                .flush();
+        }
+        mem::release_frame(frame, owner);
+        Ok(())
+    }
+
+    /// Maps one page of an area, allocating its frame if the area has private memory
+    fn map_page(&mut self, vma: &Vma, page: Page<Size4KiB>) -> Result<(), VmError> {
+        let owner = Owner::Process(self.id);
+        let offset = vma.offset + (page.start_address().as_u64() - vma.start);
+        let frame = match &vma.backing {
+            Backing::Anonymous | Backing::File(_) => {
+                let frame = mem::charged(owner)
+                    .allocate_frame()
+                    .ok_or(VmError::OutOfMemory)?;
+                zero_frame(frame);
+                if let Backing::File(data) = vma.backing {
+                    let from = (offset as usize).min(data.len());
+                    let to = (offset + PAGE_SIZE).min(data.len() as u64) as usize;
+                    unsafe {
+                        core::ptr::copy_nonoverlapping(
+                            data[from..to].as_ptr(),
+                            (physical_offset() + frame.start_address().as_u64()) as *mut u8,
+                            to - from,
+                        );
+                    }
+                }
+                frame
+            }
+            Backing::Device(base) => PhysFrame::containing_address(*base + offset),
+            Backing::Shared(shared) => shared.frames[(offset / PAGE_SIZE) as usize],
+        };
+
+        let result = unsafe {
+            self.get_pt()
+                .map_to(page, frame, vma.page_flags(), &mut mem::charged(owner))
+        };
+        match result {
+            Ok(flush) => {
+                flush.flush();
+                Ok(())
+            }
+            Err(_) => {
+                if vma.owns_frames() {
+                    unsafe { mem::charged(owner).deallocate_frame(frame) };
+                }
+                Err(VmError::OutOfMemory)
+            }
+        }
+    }
+
+    /// Maps device memory up front, everything else is mapped on the first fault
+    fn populate(&mut self, vma: &Vma) -> Result<(), VmError> {
+        if let Backing::Device(_) = vma.backing {
+            for page in vma_pages(vma) {
+                self.map_page(vma, page)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Maps the page behind a user page fault if the address is part of an area that allows the
+    /// access, growing the stack down to it if needed. Returns false if the fault is an error.
+    pub fn handle_fault(&mut self, address: u64, write: bool, execute: bool) -> bool {
+        let vma = match self.vmas.find(address) {
+            Some(vma) => vma.clone(),
+            None => match self.vmas.grow_down(address, PROCESS_STACK_LIMIT) {
+                Some(vma) => vma,
+                None => return false,
+            },
+        };
+
+        let protection = vma.protection;
+        if protection == Protection::NONE
+            || (write && !protection.contains(Protection::WRITE))
+            || (execute && !protection.contains(Protection::EXEC))
+        {
+            return false;
+        }
+
+        let page = Page::containing_address(VirtAddr::new(address));
+        match self.get_pt().translate(page.start_address()) {
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size4KiB(frame),
+                flags,
+                ..
+            } if write && flags.contains(COPY_ON_WRITE) => {
+                self.copy_on_write(&vma, page, frame).is_ok()
+            }
+            // Any other fault on a mapped page is a real protection fault
+            TranslateResult::Mapped { .. } => false,
+            _ => self.map_page(&vma, page).is_ok(),
+        }
+    }
+
+    /// Copies user memory at `address` into `buffer` through the physmap, so it works from the
+    /// kernel address space. Pages that aren't mapped yet are faulted in.
+    pub fn copy_from_user(&mut self, address: u64, buffer: &mut [u8]) -> Result<(), VmError> {
+        let end = address
+            .checked_add(buffer.len() as u64)
+            .filter(|&end| end <= PROCESS_MMAP_END)
+            .ok_or(VmError::InvalidArgument)?;
+
+        let mut copied = 0;
+        let mut current = address;
+        while current < end {
+            let readable = self
+                .vmas
+                .find(current)
+                .map_or(false, |vma| vma.protection.contains(Protection::READ));
+            if !readable {
+                return Err(VmError::NotMapped);
+            }
+
+            let virt = VirtAddr::new(current);
+            let phys = match self.get_pt().translate_addr(virt) {
+                Some(phys) => phys,
+                None if self.handle_fault(current, false, false) => self
+                    .get_pt()
+                    .translate_addr(virt)
+                    .ok_or(VmError::NotMapped)?,
+                None => return Err(VmError::NotMapped),
+            };
+
+            let size = ((vma::page_align_down(current) + PAGE_SIZE).min(end) - current) as usize;
+            let source = (physical_offset() + phys.as_u64()) as *const u8;
+            unsafe {
+                core::ptr::copy_nonoverlapping(source, buffer[copied..].as_mut_ptr(), size)
+            };
+            copied += size;
+            current += size as u64;
+        }
+        Ok(())
+    }
+
+    /// Unmaps whatever pages of an area are mapped
+    fn unmap_pages(&mut self, vma: &Vma) {
+        let owner = Owner::Process(self.id);
+        let owns_frames = vma.owns_frames();
+        let mut mapper = self.get_pt();
+        for page in vma_pages(vma) {
+            if let Ok((frame, flush)) = mapper.unmap(page) {
+                flush.flush();
+                if owns_frames {
+                    mem::release_frame(frame, owner);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Process {
+    /// Frees the frames of private areas and the page tables of the address space. Kernel half
+    /// tables still shared with a fork are left to it. Must not run on the active address space.
+    fn drop(&mut self) {
+        let owner = Owner::Process(self.id);
+        let user_entries = (PROCESS_MMAP_END >> 39) as usize;
+
+        for i4 in 0..512 {
+            let entry = &self.address_space[i4];
+            if entry.is_unused() {
+                continue;
+            }
+            let frame = PhysFrame::containing_address(entry.addr());
+            if i4 >= user_entries && mem::is_shared(frame) {
+                mem::release_frame(frame, owner);
+            } else {
+                // Kernel half tables inherited from a fork stay charged to the process that made them
+                let tables = mem::shared_owner(frame).unwrap_or(owner);
+                self.free_table(entry.addr(), 3, (i4 << 39) as u64, i4 < user_entries, tables);
+            }
+        }
+        self.address_space.zero();
+    }
+}
+
+impl Process {
+    /// Frees a page table at `level` covering memory from `base` and the tables below it, charged
+    /// to `tables`. Frames mapped in private areas are released if `user` is set, everything else
+    /// mapped stays.
+    fn free_table(&self, address: PhysAddr, level: usize, base: u64, user: bool, tables: Owner) {
+        let owner = Owner::Process(self.id);
+        let entry_size = 1u64 << (12 + 9 * (level - 1));
+
+        for (index, entry) in table(address).iter().enumerate() {
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) {
+                continue;
+            }
+            let address = base + index as u64 * entry_size;
+            if level > 1 && !flags.contains(PageTableFlags::HUGE_PAGE) {
+                self.free_table(entry.addr(), level - 1, address, user, tables);
+            } else if user && self.vmas.find(address).map_or(false, |vma| vma.owns_frames()) {
+                mem::release_frame(PhysFrame::containing_address(entry.addr()), owner);
+            }
+        }
+        mem::release_frame(PhysFrame::containing_address(address), tables);
+    }
+}
+
+/// Page table at a physical address, reached through the physical memory mapping
+fn table(address: PhysAddr) -> &'static mut PageTable {
+    unsafe { &mut *((physical_offset() + address.as_u64()) as *mut PageTable) }
+}
+
+/// Maps a frame of a forked address space at the same address as in the parent
+fn clone_mapping<S: PageSize>(
+    child: &mut OffsetPageTable,
+    address: u64,
+    frame: PhysAddr,
+    flags: PageTableFlags,
+    owner: Owner,
+) -> Result<(), VmError>
+where
+    for<'a> OffsetPageTable<'a>: Mapper<S>,
+{
+    unsafe {
+        child
+            .map_to(
+                Page::<S>::containing_address(VirtAddr::new(address)),
+                PhysFrame::<S>::containing_address(frame),
+                flags,
+                &mut mem::charged(owner),
+            )
+            .map_err(|_| VmError::OutOfMemory)?
+            .ignore();
+    }
+    Ok(())
+}
+
+/// Checks a page aligned user range and returns its end
+fn user_range(address: u64, size: u64) -> Result<u64, VmError> {
+    // Checked before rounding up so a huge size can't wrap
+    if size == 0 || size > PROCESS_MMAP_END || address == 0 || address % PAGE_SIZE != 0 {
+        return Err(VmError::InvalidArgument);
+    }
+    address
+        .checked_add(vma::page_align_up(size))
+        .filter(|&end| end <= PROCESS_MMAP_END)
+        .ok_or(VmError::InvalidArgument)
+}
+
+fn vma_pages(vma: &Vma) -> PageRange<Size4KiB> {
+    Page::range(
+        Page::containing_address(VirtAddr::new(vma.start)),
+        Page::containing_address(VirtAddr::new(vma.end)),
+    )
+}
+
+/// Where frames of other address spaces are reached from the current one
+fn physical_offset() -> u64 {
+    #[cfg(feature = "kernel")]
+    return memory_regions::physmap_base();
+    #[cfg(not(feature = "kernel"))]
+    return 0;
+}
+
+fn zero_frame(frame: PhysFrame) {
+    unsafe {
+        core::ptr::write_bytes(
+            (physical_offset() + frame.start_address().as_u64()) as *mut u8,
+            0,
+            PAGE_SIZE as usize,
+        );
+    }
+}
+
+/// Protection of a loadable segment as recorded in its area
+fn segment_protection(pheader: &elf::ProgramHeader) -> Protection {
+    let mut protection = Protection::READ;
+    if pheader.is_writable() {
+        protection = protection | Protection::WRITE;
+    }
+    if pheader.is_executable() {
+        protection = protection | Protection::EXEC;
+    }
+    protection
+}
+
+/// Page table flags for a loadable segment, derived from its ELF permission bits. Text ends up
+/// read-only and executable, rodata read-only and data writable and non-executable.
+pub fn segment_flags(pheader: &elf::ProgramHeader) -> PageTableFlags {
+    let mut flags = PageTableFlags::PRESENT;
+    if pheader.is_writable() {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if !pheader.is_executable() && mem::nx_enabled() {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+/// Allocates frames for a loadable segment, copies the file backed part of it and zeroes the rest,
+/// then maps the frames at the segment's virtual address plus `slide` with `flags`. Segments that
+/// don't start or end on a page boundary can share `previous`, the last page of the segment
+/// before, which keeps its frame and gets the permissions of both. Any other page that is already
+/// mapped is an error. Returns the last page of the segment. On error the pages mapped so far are
+/// left to the caller.
+pub fn load_segment(
+    elf: &elf::ElfFile<'_>,
+    pheader: &elf::ProgramHeader,
+    slide: u64,
+    flags: PageTableFlags,
+    previous: Option<Page<Size4KiB>>,
+    mapper: &mut (impl Mapper<Size4KiB> + Translate),
+    current_mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<Option<Page<Size4KiB>>, elf::LoadError> {
+    if pheader.segment_mem_size == 0 {
+        return Ok(previous);
+    }
+
+    let bad_address = elf::LoadError::BadAddress(pheader.virtual_address);
+    let segment_start = pheader.virtual_address.checked_add(slide).ok_or(bad_address)?;
+    let segment_end = segment_start
+        .checked_add(pheader.segment_mem_size)
+        .ok_or(bad_address)?;
+    let data = elf.segment(pheader).unwrap_or(&[]);
+
+    // Pages of segment virtual address
+    let pg_start = Page::<Size4KiB>::containing_address(
+        VirtAddr::try_new(segment_start).map_err(|_| bad_address)?,
+    );
+    let pg_end = Page::<Size4KiB>::containing_address(
+        VirtAddr::try_new(segment_end - 1).map_err(|_| bad_address)?,
+    );
+
+    for page in Page::range_inclusive(pg_start, pg_end) {
+        let shared = match mapper.translate(page.start_address()) {
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size4KiB(frame),
+                flags: page_flags,
+                ..
+            } if Some(page) == previous => Some((frame, page_flags)),
+            TranslateResult::NotMapped => None,
+            _ => return Err(elf::LoadError::Overlap(page.start_address().as_u64())),
+        };
+
+        let frame = match shared {
+            Some((frame, _)) => frame,
+            None => frame_allocator
+                .allocate_frame()
+                .ok_or(elf::LoadError::OutOfMemory)?,
+        };
+
+        let frame_ptr = frame_pointer(frame, current_mapper, frame_allocator);
+
+        // A shared page already holds the neighbouring segment's data
+        if shared.is_none() {
+            unsafe {
+                core::ptr::write_bytes(frame_ptr, 0, Size4KiB::SIZE as usize);
+            }
+        }
+
+        /* Copy the part of the segment's file data that lands in this page */
+        let page_start = page.start_address().as_u64();
+        let copy_start = page_start.max(segment_start);
+        let copy_end = (page_start + Size4KiB::SIZE).min(segment_start + data.len() as u64);
+        if copy_start < copy_end {
+            let offset = (copy_start - segment_start) as usize;
+            let len = (copy_end - copy_start) as usize;
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    data[offset..offset + len].as_ptr(),
+                    frame_ptr.add((copy_start - page_start) as usize),
+                    len,
+                );
+            }
+        }
+
+        match shared {
+            Some((_, page_flags)) => {
+                let mut merged = (page_flags | flags) & !PageTableFlags::NO_EXECUTE;
+                if page_flags.contains(PageTableFlags::NO_EXECUTE)
+                    && flags.contains(PageTableFlags::NO_EXECUTE)
+                {
+                    merged |= PageTableFlags::NO_EXECUTE;
+                }
+                if merged.contains(PageTableFlags::WRITABLE)
+                    && !merged.contains(PageTableFlags::NO_EXECUTE)
+                {
+                    kprintln!(
+                        "Page {:x} is shared by segments and is both writable and executable",
+                        page.start_address().as_u64()
+                    );
+                }
+                unsafe {
+                    mapper
+                        .update_flags(page, merged)
+                        .expect("Unable to update elf segment flags!")
+                        .ignore();
+                }
+            }
+            None => match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+                Ok(flush) => flush.ignore(),
+                Err(e) => {
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                    return Err(map_error(e, page));
+                }
+            },
+        }
+    }
+    Ok(Some(pg_end))
+}
+
+fn map_error(error: MapToError<Size4KiB>, page: Page<Size4KiB>) -> elf::LoadError {
+    match error {
+        MapToError::FrameAllocationFailed => elf::LoadError::OutOfMemory,
+        _ => elf::LoadError::Overlap(page.start_address().as_u64()),
+    }
+}
+
+/// Size of the thread control block after the TLS block. The first word points to itself, the
+/// rest is room for things like the stack protector canary at `fs:0x28`.
+const TCB_SIZE: u64 = 64;
+
+/// Thread pointer of a process whose TLS block, described by `tls`, starts at
+/// `PROCESS_TLS_ADDRESS`. The block and the TCB have to end below the mmap window.
+fn tls_thread_pointer(tls: &elf::ProgramHeader) -> Result<u64, elf::LoadError> {
+    let bad_address = elf::LoadError::BadAddress(tls.virtual_address);
+    let align = tls.align().max(1);
+    if align > Size4KiB::SIZE {
+        return Err(bad_address);
+    }
+
+    let block_size = tls
+        .segment_mem_size
+        .checked_add(align - 1)
+        .ok_or(bad_address)?
+        / align
+        * align;
+    memory_regions::PROCESS_TLS_ADDRESS
+        .checked_add(block_size)
+        .filter(|&thread_pointer| thread_pointer <= PROCESS_MMAP_START - TCB_SIZE)
+        .ok_or(bad_address)
+}
+
+/// Allocates the TLS block of a process at `PROCESS_TLS_ADDRESS` with the x86-64 variant II
+/// layout: the `.tdata` image followed by zeroed `.tbss` ends right at `thread_pointer`, which
+/// points at the TCB.
+fn load_tls(
+    elf: &elf::ElfFile<'_>,
+    tls: &elf::ProgramHeader,
+    thread_pointer: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    current_mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<(), elf::LoadError> {
+    let data = elf.segment(tls).unwrap_or(&[]);
+
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    if mem::nx_enabled() {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+
+    let pages = Page::<Size4KiB>::range(
+        Page::containing_address(VirtAddr::new(memory_regions::PROCESS_TLS_ADDRESS)),
+        Page::containing_address(VirtAddr::new(thread_pointer + TCB_SIZE + Size4KiB::SIZE - 1)),
+    );
+    for page in pages {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(elf::LoadError::OutOfMemory)?;
+        let frame_ptr = frame_pointer(frame, current_mapper, frame_allocator);
+        unsafe {
+            core::ptr::write_bytes(frame_ptr, 0, Size4KiB::SIZE as usize);
+        }
+
+        /* Copy the .tdata image and the TCB self pointer where they overlap this page */
+        let page_start = page.start_address().as_u64();
+        let page_end = page_start + Size4KiB::SIZE;
+        let image_start = memory_regions::PROCESS_TLS_ADDRESS;
+        let copy_start = page_start.max(image_start);
+        let copy_end = page_end.min(image_start + data.len() as u64);
+        if copy_start < copy_end {
+            let offset = (copy_start - image_start) as usize;
+            let len = (copy_end - copy_start) as usize;
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    data[offset..offset + len].as_ptr(),
+                    frame_ptr.add((copy_start - page_start) as usize),
+                    len,
+                );
+            }
+        }
+        if (page_start..page_end).contains(&thread_pointer) {
+            unsafe {
+                core::ptr::write_unaligned(
+                    frame_ptr.add((thread_pointer - page_start) as usize) as *mut u64,
+                    thread_pointer,
+                );
+            }
+        }
+
+        match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+            Ok(flush) => flush.ignore(),
+            Err(e) => {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+                return Err(map_error(e, page));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Pointer to write a frame through from the current address space. The kernel reaches every
+/// frame through the physical memory mapping, the loader identity maps it.
+fn frame_pointer(
+    frame: PhysFrame,
+    current_mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> *mut u8 {
+    #[cfg(feature = "kernel")]
+    {
+        let _ = (current_mapper, frame_allocator);
+        (memory_regions::physmap_base() + frame.start_address().as_u64()) as *mut u8
+    }
+    #[cfg(not(feature = "kernel"))]
+    unsafe {
+        // Identity map frame in current address space for copying
+        if let Ok(flush) = current_mapper.identity_map(
+            frame,
+            PageTableFlags::WRITABLE | PageTableFlags::PRESENT,
+            frame_allocator,
+        ) {
+            flush.flush();
+        }
+        frame.start_address().as_u64() as *mut u8
+    }
+}
+
+/// Applies the dynamic relocations of a position independent image that was loaded `slide` bytes
+/// above its link address. Words are patched through the physical memory mapping of `mapper`, so
+/// relocations in read-only segments work too. Symbols the image doesn't define are looked up with
+/// `imports`. Returns the number of relocations applied.
+pub fn relocate(
+    elf: &elf::ElfFile<'_>,
+    slide: u64,
+    mapper: &OffsetPageTable,
+    imports: impl Fn(&str) -> Option<u64>,
+) -> Result<usize, elf::RelocationError> {
+    let mut count = 0;
+    for rela in elf.relocations() {
+        let value = match rela.relocation_type() {
+            elf::Rela::X86_64_NONE => continue,
+            elf::Rela::X86_64_RELATIVE => slide.wrapping_add(rela.addend as u64),
+            rtype @ (elf::Rela::X86_64_64
+            | elf::Rela::X86_64_GLOB_DAT
+            | elf::Rela::X86_64_JUMP_SLOT) => {
+                let index = rela.symbol();
+                let symbol = elf
+                    .dynamic_symbol(index)
+                    .ok_or(elf::RelocationError::UndefinedSymbol(index))?;
+
+                let address = if !symbol.is_undefined() {
+                    symbol.value.wrapping_add(slide)
+                } else {
+                    match elf.dynamic_string(symbol.name).and_then(&imports) {
+                        Some(address) => address,
+                        // Unresolved weak imports are null
+                        None if symbol.is_weak() => 0,
+                        None => return Err(elf::RelocationError::UndefinedSymbol(index)),
+                    }
+                };
+
+                if rtype == elf::Rela::X86_64_64 {
+                    address.wrapping_add(rela.addend as u64)
+                } else {
+                    address
+                }
+            }
+            rtype => return Err(elf::RelocationError::Unsupported(rtype)),
+        };
+
+        let target = rela.offset.wrapping_add(slide);
+        let phys = VirtAddr::try_new(target)
+            .ok()
+            .and_then(|address| mapper.translate_addr(address))
+            .ok_or(elf::RelocationError::Unmapped(target))?;
+        unsafe {
+            core::ptr::write_unaligned(
+                (mapper.phys_offset() + phys.as_u64()).as_mut_ptr::<u64>(),
+                value,
+            );
+        }
+        count += 1;
     }
+    Ok(count)
 }
@@ -85,6 +85,18 @@ impl SerialPort {
         }
     }
 
+    /// Writes bytes as they are, without turning `\n` into `\r\n`
+    pub fn write_raw(&self, value: &[u8]) {
+        if self.enabled {
+            for &b in value {
+                unsafe {
+                    while self.can_write() {}
+                    util::out8(self.address, b);
+                }
+            }
+        }
+    }
+
     pub fn write(&self, value: &[u8]) {
         if self.enabled {
             for &b in value {
@@ -0,0 +1,444 @@
+use core::{
+    alloc::Layout,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+};
+
+use spinning_top::Spinlock;
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB},
+    PhysAddr,
+};
+
+use crate::mem::{self, Owner, Zone};
+
+const PAGE_SIZE: usize = 4096;
+/* Slabs of larger objects span several pages so the header doesn't cost a whole object */
+const LARGE_OBJECT: usize = 512;
+const LARGE_SLAB_SIZE: usize = 4 * PAGE_SIZE;
+/// Freed pages the page cache holds on to before giving them back to the frame allocator
+const PAGE_CACHE_LIMIT: usize = 64;
+
+/* Every slab starts with this header, the objects follow it */
+struct SlabPage {
+    next: *mut SlabPage,
+    free: *mut FreeObject,
+    in_use: usize,
+}
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+struct Slabs {
+    /// Pages with at least one free object, full pages aren't tracked until an object is freed
+    partial: *mut SlabPage,
+    empty: usize,
+    pages: usize,
+    objects: usize,
+    allocations: u64,
+    frees: u64,
+    registered: bool,
+}
+
+unsafe impl Send for Slabs {}
+
+impl Slabs {
+    const fn new() -> Slabs {
+        Slabs {
+            partial: ptr::null_mut(),
+            empty: 0,
+            pages: 0,
+            objects: 0,
+            allocations: 0,
+            frees: 0,
+            registered: false,
+        }
+    }
+
+    unsafe fn unlink(&mut self, page: *mut SlabPage) {
+        let mut link = &mut self.partial;
+        while !link.is_null() {
+            if *link == page {
+                *link = (*page).next;
+                return;
+            }
+            link = &mut (**link).next;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CacheStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    /// Objects in use
+    pub objects: usize,
+    pub pages: usize,
+    pub allocations: u64,
+    pub frees: u64,
+}
+
+/// Objects of one size carved out of whole frames
+pub struct SlabCache {
+    name: &'static str,
+    size: usize,
+    align: usize,
+    /// Bytes of each slab, slabs are aligned to their size
+    slab_size: usize,
+    slabs: Spinlock<Slabs>,
+}
+
+impl SlabCache {
+    pub const fn new(name: &'static str, size: usize, align: usize) -> SlabCache {
+        let align = if align < 8 { 8 } else { align };
+        let size = if size < 8 { 8 } else { size };
+        let size = (size + align - 1) & !(align - 1);
+        SlabCache {
+            name,
+            size,
+            align,
+            slab_size: if size >= LARGE_OBJECT { LARGE_SLAB_SIZE } else { PAGE_SIZE },
+            slabs: Spinlock::new(Slabs::new()),
+        }
+    }
+
+    fn slab_pages(&self) -> usize {
+        self.slab_size / PAGE_SIZE
+    }
+
+    fn first_object(&self) -> usize {
+        (core::mem::size_of::<SlabPage>() + self.align - 1) & !(self.align - 1)
+    }
+
+    pub fn allocate(&'static self) -> Option<NonNull<u8>> {
+        let mut slabs = self.slabs.lock();
+        if slabs.partial.is_null() {
+            slabs.partial = self.grow()?;
+            slabs.pages += 1;
+            slabs.empty += 1;
+            if !slabs.registered {
+                slabs.registered = true;
+                register(self);
+            }
+        }
+
+        unsafe {
+            let page = slabs.partial;
+            let object = (*page).free;
+            (*page).free = (*object).next;
+            if (*page).in_use == 0 {
+                slabs.empty -= 1;
+            }
+            (*page).in_use += 1;
+            if (*page).free.is_null() {
+                slabs.partial = (*page).next;
+                (*page).next = ptr::null_mut();
+            }
+            slabs.objects += 1;
+            slabs.allocations += 1;
+            NonNull::new(object as *mut u8)
+        }
+    }
+
+    /// `object` has to come from `allocate` of this cache
+    pub unsafe fn free(&self, object: NonNull<u8>) {
+        let mut slabs = self.slabs.lock();
+        let page = (object.as_ptr() as usize & !(self.slab_size - 1)) as *mut SlabPage;
+        let object = object.as_ptr() as *mut FreeObject;
+
+        if (*page).free.is_null() {
+            (*page).next = slabs.partial;
+            slabs.partial = page;
+        }
+        (*object).next = (*page).free;
+        (*page).free = object;
+        (*page).in_use -= 1;
+        slabs.objects -= 1;
+        slabs.frees += 1;
+
+        if (*page).in_use == 0 {
+            slabs.empty += 1;
+            // One empty page stays so a cache going back and forth doesn't hit the frame allocator
+            if slabs.empty > 1 {
+                slabs.unlink(page);
+                slabs.empty -= 1;
+                slabs.pages -= 1;
+                release_frames(page as usize, self.slab_pages(), Owner::Heap);
+            }
+        }
+    }
+
+    /// Sets up a new slab with all objects free
+    fn grow(&self) -> Option<*mut SlabPage> {
+        let first = self.first_object();
+        let count = self.slab_size.saturating_sub(first) / self.size;
+        if count == 0 {
+            return None;
+        }
+
+        let address = allocate_frames(self.slab_pages(), Owner::Heap)?;
+        let page = address as *mut SlabPage;
+        unsafe {
+            let mut free = ptr::null_mut();
+            for index in (0..count).rev() {
+                let object = (address + first + index * self.size) as *mut FreeObject;
+                (*object).next = free;
+                free = object;
+            }
+            page.write(SlabPage {
+                next: ptr::null_mut(),
+                free,
+                in_use: 0,
+            });
+        }
+        Some(page)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let slabs = self.slabs.lock();
+        CacheStats {
+            name: self.name,
+            object_size: self.size,
+            objects: slabs.objects,
+            pages: slabs.pages * self.slab_pages(),
+            allocations: slabs.allocations,
+            frees: slabs.frees,
+        }
+    }
+}
+
+struct Pages {
+    free: *mut FreeObject,
+    cached: usize,
+    in_use: usize,
+    allocations: u64,
+    frees: u64,
+}
+
+unsafe impl Send for Pages {}
+
+/// Whole pages charged to `owner`. Freed pages are kept for reuse up to `PAGE_CACHE_LIMIT`.
+pub struct PageCache {
+    name: &'static str,
+    owner: Owner,
+    pages: Spinlock<Pages>,
+}
+
+impl PageCache {
+    pub const fn new(name: &'static str, owner: Owner) -> PageCache {
+        PageCache {
+            name,
+            owner,
+            pages: Spinlock::new(Pages {
+                free: ptr::null_mut(),
+                cached: 0,
+                in_use: 0,
+                allocations: 0,
+                frees: 0,
+            }),
+        }
+    }
+
+    pub fn allocate(&self) -> Option<NonNull<u8>> {
+        let mut pages = self.pages.lock();
+        let address = if pages.free.is_null() {
+            allocate_frames(1, self.owner)?
+        } else {
+            let page = pages.free;
+            pages.free = unsafe { (*page).next };
+            pages.cached -= 1;
+            page as usize
+        };
+        pages.in_use += 1;
+        pages.allocations += 1;
+        NonNull::new(address as *mut u8)
+    }
+
+    /// `page` has to come from `allocate` of this cache
+    pub unsafe fn free(&self, page: NonNull<u8>) {
+        let mut pages = self.pages.lock();
+        pages.in_use -= 1;
+        pages.frees += 1;
+        if pages.cached < PAGE_CACHE_LIMIT {
+            let page = page.as_ptr() as *mut FreeObject;
+            (*page).next = pages.free;
+            pages.free = page;
+            pages.cached += 1;
+        } else {
+            release_frames(page.as_ptr() as usize, 1, self.owner);
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let pages = self.pages.lock();
+        CacheStats {
+            name: self.name,
+            object_size: PAGE_SIZE,
+            objects: pages.in_use,
+            pages: pages.in_use + pages.cached,
+            allocations: pages.allocations,
+            frees: pages.frees,
+        }
+    }
+}
+
+/// Cache of `T`s for objects the kernel allocates and frees often
+pub struct ObjectCache<T> {
+    cache: SlabCache,
+    phantom: PhantomData<fn() -> T>,
+}
+
+impl<T> ObjectCache<T> {
+    pub const fn new(name: &'static str) -> ObjectCache<T> {
+        ObjectCache {
+            cache: SlabCache::new(name, core::mem::size_of::<T>(), core::mem::align_of::<T>()),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Moves `value` into the cache, it is given back if there is no memory
+    pub fn alloc(&'static self, value: T) -> Result<SlabBox<T>, T> {
+        match self.cache.allocate() {
+            Some(ptr) => {
+                let ptr = ptr.cast::<T>();
+                unsafe { ptr.as_ptr().write(value) };
+                Ok(SlabBox {
+                    ptr,
+                    cache: &self.cache,
+                })
+            }
+            None => Err(value),
+        }
+    }
+}
+
+/// Owned object in an `ObjectCache`, returned to it on drop
+pub struct SlabBox<T> {
+    ptr: NonNull<T>,
+    cache: &'static SlabCache,
+}
+
+unsafe impl<T: Send> Send for SlabBox<T> {}
+unsafe impl<T: Sync> Sync for SlabBox<T> {}
+
+impl<T> Deref for SlabBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.ptr.as_ptr());
+            self.cache.free(self.ptr.cast());
+        }
+    }
+}
+
+/* Caches behind the global allocator, larger or more aligned allocations go to the heap */
+static SIZE_CLASSES: [SlabCache; 7] = [
+    SlabCache::new("size-16", 16, 16),
+    SlabCache::new("size-32", 32, 16),
+    SlabCache::new("size-64", 64, 16),
+    SlabCache::new("size-128", 128, 16),
+    SlabCache::new("size-256", 256, 16),
+    SlabCache::new("size-512", 512, 16),
+    SlabCache::new("size-1024", 1024, 16),
+];
+static PAGES: PageCache = PageCache::new("page", Owner::Heap);
+static PAGE_TABLES: PageCache = PageCache::new("page-table", Owner::PageTable);
+
+/// Slab caches that have been used, for statistics
+static mut CACHES: [Option<&'static SlabCache>; 32] = [None; 32];
+
+fn register(cache: &'static SlabCache) {
+    unsafe {
+        if let Some(slot) = CACHES.iter_mut().find(|c| c.is_none()) {
+            *slot = Some(cache);
+        }
+    }
+}
+
+fn size_class(layout: Layout) -> Option<&'static SlabCache> {
+    SIZE_CLASSES
+        .iter()
+        .find(|cache| cache.size >= layout.size() && cache.align >= layout.align())
+}
+
+fn is_page(layout: Layout) -> bool {
+    layout.size() == PAGE_SIZE && layout.align() <= PAGE_SIZE
+}
+
+/// Allocates from the size classes or the page cache. `None` if the layout is too large for them
+/// or there is no memory.
+pub fn allocate(layout: Layout) -> Option<*mut u8> {
+    let ptr = if is_page(layout) {
+        PAGES.allocate()?
+    } else {
+        size_class(layout)?.allocate()?
+    };
+    Some(ptr.as_ptr())
+}
+
+/// Frees an allocation `allocate` made for the same layout
+pub unsafe fn deallocate(ptr: *mut u8, layout: Layout) {
+    let ptr = NonNull::new_unchecked(ptr);
+    if is_page(layout) {
+        PAGES.free(ptr);
+    } else if let Some(cache) = size_class(layout) {
+        cache.free(ptr);
+    }
+}
+
+/// Statistics of every cache in use
+pub fn stats() -> impl Iterator<Item = CacheStats> {
+    unsafe { CACHES.iter() }
+        .flatten()
+        .map(|cache| cache.stats())
+        .chain([PAGES.stats(), PAGE_TABLES.stats()])
+}
+
+/// Frame allocator for kernel page tables, backed by the page table cache
+pub struct PageTables;
+
+pub fn page_tables() -> PageTables {
+    PageTables
+}
+
+unsafe impl FrameAllocator<Size4KiB> for PageTables {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let page = PAGE_TABLES.allocate()?.as_ptr() as u64;
+        Some(PhysFrame::containing_address(PhysAddr::new(page - mem::physical_offset())))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for PageTables {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let page = mem::physical_offset() + frame.start_address().as_u64();
+        PAGE_TABLES.free(NonNull::new_unchecked(page as *mut u8));
+    }
+}
+
+/// `count` contiguous frames aligned to their size, returned at their physical memory mapping
+fn allocate_frames(count: usize, owner: Owner) -> Option<usize> {
+    let frame = mem::allocator()
+        .lock()
+        .allocate_contiguous(count, count, Zone::Normal, owner)?;
+    Some((mem::physical_offset() + frame.start_address().as_u64()) as usize)
+}
+
+fn release_frames(address: usize, count: usize, owner: Owner) {
+    let frame = PhysFrame::containing_address(PhysAddr::new(address as u64 - mem::physical_offset()));
+    mem::allocator().lock().free_contiguous(frame, count, owner);
+}
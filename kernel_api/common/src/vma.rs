@@ -0,0 +1,308 @@
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use x86_64::{
+    structures::paging::{FrameDeallocator, PageTableFlags, PhysFrame},
+    PhysAddr,
+};
+
+use crate::{
+    mem::{self, Owner},
+    memory_regions::{PROCESS_MMAP_END, PROCESS_MMAP_START},
+};
+
+pub const PAGE_SIZE: u64 = 4096;
+
+/* mmap flags, the same bits as Linux where there is an equivalent */
+pub const MAP_SHARED: u64 = 0x01;
+pub const MAP_FIXED: u64 = 0x10;
+/// Maps the physical address passed as the offset, for MMIO windows of user space drivers
+pub const MAP_DEVICE: u64 = 0x100000;
+
+/// Marks private pages shared read-only with another address space, a write fault copies them
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Wraps near `u64::MAX`, user supplied values have to be bounded first
+pub fn page_align_up(value: u64) -> u64 {
+    (value + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+pub fn page_align_down(value: u64) -> u64 {
+    value & !(PAGE_SIZE - 1)
+}
+
+/// Access a process has to an area, the `PROT_*` bits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Protection(u8);
+
+impl Protection {
+    pub const NONE: Protection = Protection(0);
+    pub const READ: Protection = Protection(1);
+    pub const WRITE: Protection = Protection(2);
+    pub const EXEC: Protection = Protection(4);
+
+    pub fn from_bits(bits: u64) -> Option<Protection> {
+        if bits & !7 != 0 {
+            return None;
+        }
+        Some(Protection(bits as u8))
+    }
+
+    pub fn contains(&self, other: Protection) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Page table flags for the protection. x86 can't map pages without read access, so
+    /// `PROT_NONE` pages stay present but lose `USER_ACCESSIBLE`.
+    pub fn page_flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT;
+        if *self != Protection::NONE {
+            flags |= PageTableFlags::USER_ACCESSIBLE;
+        }
+        if self.contains(Protection::WRITE) {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !self.contains(Protection::EXEC) && mem::nx_enabled() {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+}
+
+impl core::ops::BitOr for Protection {
+    type Output = Protection;
+
+    fn bitor(self, rhs: Protection) -> Protection {
+        Protection(self.0 | rhs.0)
+    }
+}
+
+/// Frames several address spaces map, freed when the last area using them goes away
+#[derive(Debug)]
+pub struct SharedFrames {
+    pub frames: Vec<PhysFrame>,
+    owner: Owner,
+}
+
+impl SharedFrames {
+    pub fn new(frames: Vec<PhysFrame>, owner: Owner) -> SharedFrames {
+        SharedFrames { frames, owner }
+    }
+}
+
+impl Drop for SharedFrames {
+    fn drop(&mut self) {
+        let mut allocator = mem::charged(self.owner);
+        for &frame in &self.frames {
+            unsafe { allocator.deallocate_frame(frame) };
+        }
+    }
+}
+
+/// What the pages of an area are filled with
+#[derive(Debug, Clone)]
+pub enum Backing {
+    /// Private zeroed memory
+    Anonymous,
+    /// Private copy of the data, zero past its end
+    File(&'static [u8]),
+    /// Physical memory of a device, never allocated or freed
+    Device(PhysAddr),
+    Shared(Arc<SharedFrames>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Vma {
+    pub start: u64,
+    pub end: u64,
+    pub protection: Protection,
+    pub backing: Backing,
+    /// Offset of `start` into the backing
+    pub offset: u64,
+    /// Stacks are extended down to faults below them
+    pub grows_down: bool,
+}
+
+impl Vma {
+    pub fn new(start: u64, end: u64, protection: Protection, backing: Backing) -> Vma {
+        Vma {
+            start,
+            end,
+            protection,
+            backing,
+            offset: 0,
+            grows_down: false,
+        }
+    }
+
+    pub fn contains(&self, address: u64) -> bool {
+        address >= self.start && address < self.end
+    }
+
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn page_flags(&self) -> PageTableFlags {
+        match self.backing {
+            Backing::Device(_) => {
+                self.protection.page_flags()
+                    | PageTableFlags::NO_CACHE
+                    | PageTableFlags::WRITE_THROUGH
+            }
+            _ => self.protection.page_flags(),
+        }
+    }
+
+    /// Whether the frames behind the area belong to it and are freed when it is unmapped
+    pub fn owns_frames(&self) -> bool {
+        matches!(self.backing, Backing::Anonymous | Backing::File(_))
+    }
+
+    /// Splits off the part from `at` on, which has to be a page boundary inside the area
+    fn split_off(&mut self, at: u64) -> Vma {
+        let mut tail = self.clone();
+        tail.start = at;
+        tail.offset = self.offset + (at - self.start);
+        self.end = at;
+        tail
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmError {
+    InvalidArgument,
+    OutOfMemory,
+    /// Part of the range isn't mapped
+    NotMapped,
+    /// The range is already in use
+    Overlap,
+    /// Device mappings can't cover RAM
+    Forbidden,
+}
+
+impl VmError {
+    /// Negative errno returned by the memory syscalls
+    pub fn errno(&self) -> i64 {
+        match self {
+            VmError::InvalidArgument => -22,
+            VmError::OutOfMemory | VmError::NotMapped => -12,
+            VmError::Overlap => -17,
+            VmError::Forbidden => -1,
+        }
+    }
+}
+
+/// Areas of a process address space, keyed by start address. Areas never overlap.
+#[derive(Debug, Default, Clone)]
+pub struct Vmas {
+    areas: BTreeMap<u64, Vma>,
+}
+
+impl Vmas {
+    pub fn new() -> Vmas {
+        Vmas {
+            areas: BTreeMap::new(),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.areas.values()
+    }
+
+    pub fn find(&self, address: u64) -> Option<&Vma> {
+        self.areas
+            .range(..=address)
+            .next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| vma.contains(address))
+    }
+
+    pub fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.areas
+            .range(..end)
+            .next_back()
+            .map_or(false, |(_, vma)| vma.end > start)
+    }
+
+    pub fn insert(&mut self, vma: Vma) -> Result<(), VmError> {
+        if vma.start >= vma.end || self.overlaps(vma.start, vma.end) {
+            return Err(VmError::Overlap);
+        }
+        self.areas.insert(vma.start, vma);
+        Ok(())
+    }
+
+    /// Extends the stack area above `address` down to its page, as long as the stack stays within
+    /// `limit` bytes and doesn't run into another area. Returns the grown area.
+    pub fn grow_down(&mut self, address: u64, limit: u64) -> Option<Vma> {
+        let start = page_align_down(address);
+        let (&key, vma) = self.areas.range(address..).next()?;
+        if !vma.grows_down || vma.end - start > limit || self.overlaps(start, key) {
+            return None;
+        }
+
+        let mut vma = self.areas.remove(&key)?;
+        vma.start = start;
+        self.areas.insert(start, vma.clone());
+        Some(vma)
+    }
+
+    /// Lowest free range of `size` bytes in the mmap window
+    pub fn find_free(&self, size: u64) -> Option<u64> {
+        let mut start = PROCESS_MMAP_START;
+        // An area starting below the window can still reach into it
+        let below = self.areas.range(..PROCESS_MMAP_START).next_back();
+        let areas = below.into_iter().chain(self.areas.range(PROCESS_MMAP_START..));
+        for vma in areas.map(|(_, vma)| vma) {
+            if vma.start >= start + size {
+                break;
+            }
+            start = start.max(vma.end);
+        }
+        (start + size <= PROCESS_MMAP_END).then(|| start)
+    }
+
+    /// Makes `at` an area boundary if an area spans it
+    fn split(&mut self, at: u64) {
+        let key = match self.find(at) {
+            Some(vma) if vma.start != at => vma.start,
+            _ => return,
+        };
+        let tail = self.areas.get_mut(&key).unwrap().split_off(at);
+        self.areas.insert(at, tail);
+    }
+
+    /// Removes everything in `[start, end)` and returns the removed parts
+    pub fn remove(&mut self, start: u64, end: u64) -> Vec<Vma> {
+        self.split(start);
+        self.split(end);
+        let keys: Vec<u64> = self.areas.range(start..end).map(|(&key, _)| key).collect();
+        keys.into_iter()
+            .filter_map(|key| self.areas.remove(&key))
+            .collect()
+    }
+
+    /// Changes the protection of `[start, end)`, which has to be mapped without holes. Returns
+    /// the areas that changed.
+    pub fn protect(
+        &mut self,
+        start: u64,
+        end: u64,
+        protection: Protection,
+    ) -> Result<Vec<Vma>, VmError> {
+        let mut address = start;
+        while address < end {
+            address = self.find(address).ok_or(VmError::NotMapped)?.end;
+        }
+
+        self.split(start);
+        self.split(end);
+        Ok(self
+            .areas
+            .range_mut(start..end)
+            .map(|(_, vma)| {
+                vma.protection = protection;
+                vma.clone()
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,11 @@
+use std::{env, path::PathBuf};
+
+fn main() {
+    // libkapi.so is built into the same target directory as the drivers
+    if env::var_os("CARGO_FEATURE_KAPI").is_some() {
+        let manifest = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+        let profile = env::var("PROFILE").unwrap();
+        let dir = manifest.join("../../target/driver_target").join(profile);
+        println!("cargo:rustc-link-search=native={}", dir.display());
+    }
+}
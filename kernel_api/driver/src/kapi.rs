@@ -0,0 +1,21 @@
+//! Kernel services for shared object drivers, resolved by the kernel's module loader
+
+#[link(name = "kapi")]
+extern "C" {
+    fn kapi_log(message: *const u8, len: usize);
+    fn kapi_alloc(size: usize, align: usize) -> *mut u8;
+    fn kapi_free(ptr: *mut u8, size: usize, align: usize);
+}
+
+pub fn log(message: &str) {
+    unsafe { kapi_log(message.as_ptr(), message.len()) }
+}
+
+/// Allocates from the kernel heap, null on failure
+pub fn alloc(size: usize, align: usize) -> *mut u8 {
+    unsafe { kapi_alloc(size, align) }
+}
+
+pub unsafe fn free(ptr: *mut u8, size: usize, align: usize) {
+    kapi_free(ptr, size, align)
+}
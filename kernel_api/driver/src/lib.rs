@@ -1,5 +1,8 @@
 #![cfg_attr(not(test), no_std)]
 
+#[cfg(feature = "kapi")]
+pub mod kapi;
+
 pub trait DriverCore {
     #[no_mangle]
     fn init();
@@ -8,8 +11,23 @@ pub trait DriverCore {
     fn uninit();
 }
 
+/// Sets up a driver crate. Given the driver's `DriverCore` type it also exports the
+/// `driver_init`/`driver_uninit` entry points the kernel's module loader calls.
 #[macro_export]
 macro_rules! driver {
+    ($core:ty) => {
+        #[no_mangle]
+        pub extern "C" fn driver_init() {
+            <$core as $crate::DriverCore>::init()
+        }
+
+        #[no_mangle]
+        pub extern "C" fn driver_uninit() {
+            <$core as $crate::DriverCore>::uninit()
+        }
+
+        $crate::driver!();
+    };
     () => {
         use core::panic::PanicInfo;
 
@@ -0,0 +1,26 @@
+#![no_std]
+//! Stubs for the kernel API so drivers can link against `libkapi.so` and get a `DT_NEEDED` entry
+//! for it. The module loader resolves these imports to the kernel's own functions, none of the
+//! code here ever runs.
+
+use core::panic::PanicInfo;
+
+#[no_mangle]
+pub extern "C" fn kapi_log(_message: *const u8, _len: usize) {
+    unreachable!()
+}
+
+#[no_mangle]
+pub extern "C" fn kapi_alloc(_size: usize, _align: usize) -> *mut u8 {
+    unreachable!()
+}
+
+#[no_mangle]
+pub extern "C" fn kapi_free(_ptr: *mut u8, _size: usize, _align: usize) {
+    unreachable!()
+}
+
+#[panic_handler]
+fn panic(_: &PanicInfo) -> ! {
+    loop {}
+}
@@ -143,6 +143,7 @@ pub fn generate_isrs(_: TokenStream) -> TokenStream {
             r#"
             extern "x86-interrupt" fn _isr_{0}(mut stack_frame: idt::InterruptStackFrame) {{
                 interrupt_begin!();
+                common::cpu::clac();
                 let mut cpu: *const CpuSnapshot = core::ptr::null_mut();
                 unsafe {{
                     asm!("mov rdx, rsp", options(nomem, nostack)); // Save old stack
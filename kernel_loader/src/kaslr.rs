@@ -0,0 +1,90 @@
+use core::arch::{
+    asm,
+    x86_64::{__cpuid, _rdtsc},
+};
+
+use common::{
+    efi, kprintln,
+    memory_regions::{
+        MemoryLayout, HEAP_SLIDE_WINDOW, HEAP_START, KERNEL_SLIDE_WINDOW, PAGE_TABLE_OFFSET,
+        PHYSMAP_SLIDE_WINDOW,
+    },
+    size_gb, size_mb,
+};
+
+/// Gathers a seed for the kernel layout. The firmware RNG is only reachable while boot services
+/// are up, so this has to run before the memory map is fetched.
+pub fn seed() -> u64 {
+    if let Some(rng) = efi::rng_protocol() {
+        let mut buffer = [0u8; 8];
+        let res = rng.get_rng(&mut buffer);
+        if res == 0 {
+            kprintln!("KASLR seeded from EFI_RNG_PROTOCOL");
+            return u64::from_ne_bytes(buffer);
+        }
+        kprintln!("An error occured! {:x} GetRNG", res);
+    }
+
+    if let Some(value) = rdrand() {
+        kprintln!("KASLR seeded from RDRAND");
+        return value;
+    }
+
+    kprintln!("KASLR seeded from RDTSC, the layout is predictable!");
+    unsafe { _rdtsc() }
+}
+
+fn rdrand() -> Option<u64> {
+    let features = unsafe { __cpuid(1) };
+    if features.ecx & (1 << 30) == 0 {
+        return None;
+    }
+
+    // RDRAND can transiently run out of entropy, the SDM recommends 10 retries
+    for _ in 0..10 {
+        let value: u64;
+        let success: u8;
+        unsafe {
+            asm!(
+                "rdrand {}",
+                "setc {}",
+                out(reg) value,
+                out(reg_byte) success,
+                options(nomem, nostack)
+            );
+        }
+        if success == 1 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// splitmix64, so one seed gives independent slides for every region
+struct SplitMix(u64);
+
+impl SplitMix {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    /// A multiple of `align` below `window`
+    fn slide(&mut self, window: u64, align: u64) -> u64 {
+        (self.next() % (window / align)) * align
+    }
+}
+
+pub fn randomize(seed: u64) -> MemoryLayout {
+    let mut rng = SplitMix(seed);
+
+    MemoryLayout {
+        kernel_slide: rng.slide(KERNEL_SLIDE_WINDOW, size_mb!(2)),
+        heap_start: HEAP_START + rng.slide(HEAP_SLIDE_WINDOW as u64, size_mb!(2)) as usize,
+        // The physical memory map is made of 1GiB pages
+        physmap: PAGE_TABLE_OFFSET + rng.slide(PHYSMAP_SLIDE_WINDOW, size_gb!(1)),
+    }
+}
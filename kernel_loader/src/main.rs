@@ -5,21 +5,29 @@
 #![feature(arbitrary_enum_discriminant)]
 #![allow(unconditional_panic)]
 #![feature(box_syntax)]
+#![feature(naked_functions)]
 
 extern crate alloc;
 
+mod kaslr;
+mod menu;
+
 use core::mem::align_of_val;
 use core::panic::PanicInfo;
 use core::{alloc::Layout, arch::asm};
 
+use alloc::boxed::Box;
 use alloc::rc::Rc;
+use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use common::efi::{MemoryDescriptor, GLOBAL_SYSTEM_TABLE};
 use common::mem::PageTableFrameAllocator;
 use common::util::{Align2MB, Align4096};
 use common::x86_64::structures::paging::page::PageRangeInclusive;
-use common::{include_bytes_align_as, kprint, util};
+use common::memory_map::PhysicalMemoryMap;
+use common::mmio::CacheMode;
+use common::{include_bytes_align_as, kprint, memory_regions, util};
 use macros::wchar;
 
 use common::{
@@ -77,66 +85,25 @@ fn run(image_handle: efi::Handle, system_table: *mut efi::SystemTable) -> ! {
 
     //let base = efi::get_image_base(image_handle);
     //kprintln!("Entry: {:x}", base);
-    || {
-        let volume = unsafe { &*efi::io_volume(image_handle) };
-        let mut fileio: *const FileProtocol = core::ptr::null();
-        let res = (volume.open_volume)(volume as _, &mut fileio);
-        if res != 0 {
-            kprintln!("An error occured! {:x} OpenVolume(SFSP)", res);
-        }
-        let fileio = unsafe { &*fileio };
-        let mut newfileio: *const FileProtocol = core::ptr::null();
-
-        let res = (fileio.open)(
-            fileio as _,
-            &mut newfileio,
-            wchar!("efi\\boot\\btimg.bin") as *const _,
-            FILE_MODE_READ,
-            FILE_READ_ONLY,
-        );
-        if res != 0 {
-            kprintln!("An error occured! {:x} OPEN(SFSP)", res);
-        }
-
-        let mut file_info: FileInfo = unsafe { core::mem::zeroed() };
-        let buffer: *mut FileInfo = &mut file_info;
-        let mut size = core::mem::size_of::<FileInfo>();
-
-        let res = (fileio.get_info)(newfileio, &guid::FILE_INFO, &mut size, buffer);
-
-        if res != 0 {
-            kprintln!("An error occured! {:x} GETINFO(SFSP)", res);
-        }
-
-        let mut file_data: *mut u8 = core::ptr::null_mut();
-        let efi_table = get_system_table();
-        let res = efi_table
-            .boot_services()
-            .allocate_pool(file_info.file_size, &mut file_data);
-
-        if res != 0 {
-            kprintln!("An error occured! {:x} ALLOCATEPOOL(SFSP)", res);
-        }
-
-        let copy_file_data =
-            unsafe { core::slice::from_raw_parts_mut(file_data, file_info.file_size) };
-
-        let res = efi_table.boot_services().set_watchdog_timer(0, 0);
-        if res != 0 {
-            kprintln!("An error occured! {:x} Watchdog timer (SFSP)", res);
-        }
-
-        kprintln!("{:?}", file_info);
-        let res = efi::read_fixed(
-            unsafe { &*newfileio },
-            0,
-            file_info.file_size,
-            copy_file_data,
-        );
+    let res = get_system_table().boot_services().set_watchdog_timer(0, 0);
+    if res != 0 {
+        kprintln!("An error occured! {:x} Watchdog timer (SFSP)", res);
+    }
 
-        if res != 0 {
-            kprintln!("An error occured! {:x} READ (SFSP)", res);
+    /* Pick a boot image from the menu, falling back to the one built into the loader */
+    let entry = menu::load_config(image_handle).and_then(|config| menu::choose(&config));
+    let mut path = [0; 128];
+    let file_data = match entry {
+        Some(entry) => {
+            kprintln!("Booting {:?}", entry);
+            menu::to_char16(entry.image, &mut path)
+                .and_then(|path| efi::read_file(image_handle, path))
+                .unwrap_or_else(|| {
+                    kprintln!("Unable to read {}, using the built in image", entry.image);
+                    kernel_bytes
+                })
         }
+        None => kernel_bytes,
     };
 
     // for i in file_data as usize..file_data as usize + file_info.file_size {
@@ -153,13 +120,21 @@ fn run(image_handle: efi::Handle, system_table: *mut efi::SystemTable) -> ! {
     unsafe {
         asm!("mov {}, rsp", out(reg) copy_top);
     }
+    let seed = kaslr::seed();
+
     // Iterate memorymap and exit boot services
     let (memory_map, version) = efi::get_memory_map(image_handle);
 
+    let mut layout = kaslr::randomize(seed);
+    unsafe {
+        memory_regions::set_layout(layout);
+    }
+
     // Setup global descriptor table :P
     gdt::init();
 
-    let mut frame_allocator = mem::PageTableFrameAllocator::new(memory_map);
+    let physical_map = PhysicalMemoryMap::from_efi(memory_map);
+    let frame_allocator = mem::PageTableFrameAllocator::new(physical_map);
     let mut mapper = unsafe { mem::init(frame_allocator, 0) };
 
     let mut npt = mapper.level_4_table().clone();
@@ -175,10 +150,10 @@ fn run(image_handle: efi::Handle, system_table: *mut efi::SystemTable) -> ! {
         mem::KERNEL_MAP = table as u64;
     }
 
-    allocator::init_heap_new(&mut mapper, mem::allocator().get_mut(), 0, false)
+    allocator::init_heap_new(&mut mapper, &mut mem::charged(mem::Owner::Heap), 0, false)
         .expect("Unable to create heap!");
 
-    efi::print_memory_map(memory_map);
+    mem::allocator().get_mut().memory_map().print();
 
     // let layout = Layout::from_size_align(kernel_bytes.len(), 1).unwrap();
     // kprintln!("{:?}", &layout);
@@ -193,8 +168,6 @@ fn run(image_handle: efi::Handle, system_table: *mut efi::SystemTable) -> ! {
     //     kprint!("{:02X}", b);
     // }
     // loop {}
-    let file_data = kernel_bytes;
-
     // let res = efi_table.boot_services().free_pool(copy_file_data);
     kprintln!("Potato");
 
@@ -204,8 +177,12 @@ fn run(image_handle: efi::Handle, system_table: *mut efi::SystemTable) -> ! {
     let mut image: Option<elf::ElfFile> = None;
     for file in boot_image.files() {
         kprintln!("  {}", file.name());
-        let exec_file = elf::ElfFile::new(boot_image.file_data(file));
-        image.get_or_insert(exec_file);
+        match elf::ElfFile::parse(boot_image.file_data(file)) {
+            Ok(exec_file) => {
+                image.get_or_insert(exec_file);
+            }
+            Err(e) => kprintln!("  Invalid ELF file: {:?}", e),
+        }
     }
 
     // let mut copy_bottom = 0u64;
@@ -213,10 +190,25 @@ fn run(image_handle: efi::Handle, system_table: *mut efi::SystemTable) -> ! {
     //     asm!("mov {}, rsp", out(reg) copy_bottom);
     // }
 
-    let mem = efi::get_mem_size(memory_map);
+    let kernel_image = image.expect("Unable to find kernel image!");
+    if !kernel_image.header().is_position_independent() {
+        kprintln!("Kernel isn't position independent, loading it at its link address");
+        layout.kernel_slide = 0;
+        unsafe {
+            memory_regions::set_layout(layout);
+        }
+    }
+    kprintln!("Layout {:x?}", layout);
+
+    let mem = mem::allocator().get_mut().memory_map().highest_address() as usize;
+
+    if !mem::enable_nx() {
+        kprintln!("NX is not supported, kernel data will be executable");
+    }
 
     let mut process = Process::kernel_from_elf(
-        &image.expect("Unable to find kernel image!"),
+        &kernel_image,
+        layout.kernel_slide,
         unsafe { STACK_START },
         unsafe { STACK_END },
         mem,
@@ -264,6 +256,28 @@ fn run(image_handle: efi::Handle, system_table: *mut efi::SystemTable) -> ! {
         );
     }
 
+    /* The handoff trampoline is the only loader code the kernel address space gets. It has to be
+     * mapped at the same address in both so execution survives the cr3 switch. */
+    let handoff_start = Page::<Size4KiB>::containing_address(VirtAddr::new(handoff as u64));
+    let handoff_end = Page::<Size4KiB>::containing_address(VirtAddr::new(handoff as u64 + 64));
+    for page in Page::range_inclusive(handoff_start, handoff_end) {
+        let frame = PhysFrame::<Size4KiB>::containing_address(
+            mapper
+                .translate_addr(page.start_address())
+                .expect("Unable to translate handoff trampoline!"),
+        );
+        unsafe {
+            if let Ok(flush) = process.get_pt().map_to(
+                page,
+                frame,
+                PageTableFlags::PRESENT,
+                mem::allocator().get_mut(),
+            ) {
+                flush.ignore();
+            }
+        }
+    }
+
     let mut inc = 0;
     let mut iter = move |proc: &mut Process| {
         let stack = proc.stack_base as u64;
@@ -282,13 +296,31 @@ fn run(image_handle: efi::Handle, system_table: *mut efi::SystemTable) -> ! {
             .iter()
             .map(|desc| {
                 if desc.is_runtime() {
+                    // The kernel keeps calling into these, so data has to stay writable
+                    let flags = match desc.memory_type {
+                        efi::MemoryType::RuntimeServicesCode => PageTableFlags::PRESENT,
+                        // The kernel reprograms the PAT, these have to stay uncached with it
+                        efi::MemoryType::MemoryMappedIO
+                        | efi::MemoryType::MemoryMappedIOPortSpace => {
+                            PageTableFlags::PRESENT
+                                | PageTableFlags::WRITABLE
+                                | CacheMode::Uncached.flags()
+                        }
+                        _ => PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+                    };
+                    let flags = if flags.contains(PageTableFlags::WRITABLE) && mem::nx_enabled() {
+                        flags | PageTableFlags::NO_EXECUTE
+                    } else {
+                        flags
+                    };
+
                     for i in 0..desc.size {
                         unsafe {
                             mapper.identity_map(
                                 PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(
                                     (desc.physical_address + i * 4096) as u64,
                                 )),
-                                PageTableFlags::PRESENT,
+                                flags,
                                 fa,
                             );
                             // .expect("Uanble to map in kernel process for memory descriptors!")
@@ -448,31 +480,51 @@ fn run(image_handle: efi::Handle, system_table: *mut efi::SystemTable) -> ! {
     }
 
     let mut kernel_parameters = KernelParameters {
-        memory_map: value.as_ref(),
         // boot_image: (first, last),
         boot_image: (boot_image.virtual_address(), boot_image.len() as _),
         frame_allocator: mem::allocator().lock().clone(),
         system_table: GLOBAL_SYSTEM_TABLE.load(core::sync::atomic::Ordering::SeqCst),
         heap: allocator::heap(),
+        layout,
+        // Copied to the heap, the config file buffer isn't mapped in the kernel
+        command_line: Box::leak(
+            String::from(entry.map_or("", |e| e.command_line)).into_boxed_str(),
+        ),
+        debug: entry.map_or(false, |e| e.debug),
         // page_table: npt.clone()
     };
     let val = frame.start_address().as_u64();
     kprintln!("Parameters {:p}", &kernel_parameters);
 
     unsafe {
-        asm!("", in("r13") process.stack_base, in("r14") process.entry, in("r15") &kernel_parameters);
-        //  Cr3::write(frame, Cr3Flags::empty());
-        asm!("mov cr3, {}", in(reg) val, options(nostack, preserves_flags));
-
-        asm!("mov rdi, r15");
-        asm!("mov rsp, r13");
-        asm!("mov rbp, r13");
-
-        // TODO: find a better way to do this
-        asm!("jmp r14");
+        common::x86_64::instructions::interrupts::disable();
+        handoff(
+            &kernel_parameters,
+            process.stack_base as u64,
+            process.entry as u64,
+            val,
+        );
     }
+}
 
-    loop {}
+/// Switches to the kernel address space, loads the kernel stack and jumps to the kernel entry
+/// with the parameters in rdi.
+#[naked]
+unsafe extern "sysv64" fn handoff(
+    _parameters: *const KernelParameters,
+    _stack: u64,
+    _entry: u64,
+    _page_table: u64,
+) -> ! {
+    asm!(
+        "
+    mov cr3, rcx
+    mov rsp, rsi
+    mov rbp, rsi
+    jmp rdx
+    ",
+        options(noreturn)
+    );
 }
 
 #[panic_handler]
@@ -0,0 +1,196 @@
+use core::fmt::Write;
+
+use common::{
+    efi::{self, Char16, InputKey},
+    kprintln,
+};
+use macros::wchar;
+
+const MAX_ENTRIES: usize = 8;
+const MAX_PATH: usize = 128;
+
+/// A bootable configuration from `efi\boot\boot.cfg`
+#[derive(Debug, Clone, Copy)]
+pub struct Entry {
+    pub title: &'static str,
+    /// Boot image path on the ESP
+    pub image: &'static str,
+    pub command_line: &'static str,
+    /// Makes the kernel spin until a debugger attaches
+    pub debug: bool,
+}
+
+impl Entry {
+    const fn empty() -> Entry {
+        Entry {
+            title: "",
+            image: "",
+            command_line: "",
+            debug: false,
+        }
+    }
+}
+
+/// The config file is a list of `key=value` lines. `timeout` (seconds) and `default` (entry index)
+/// apply to the whole menu, every `entry=<title>` line starts a new entry which takes the `image`,
+/// `cmdline` and `debug` lines that follow it. Lines starting with `#` are ignored.
+///
+/// ```text
+/// timeout=5
+/// default=0
+///
+/// entry=Known good
+/// image=efi\boot\btimg.bin
+///
+/// entry=Test kernel
+/// image=efi\boot\test.bin
+/// cmdline=verbose
+/// debug=1
+/// ```
+pub struct Config {
+    entries: [Entry; MAX_ENTRIES],
+    count: usize,
+    timeout: usize,
+    default: usize,
+}
+
+impl Config {
+    pub fn parse(text: &'static str) -> Config {
+        let mut config = Config {
+            entries: [Entry::empty(); MAX_ENTRIES],
+            count: 0,
+            timeout: 5,
+            default: 0,
+        };
+
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => {
+                    kprintln!("Ignoring config line {:?}", line);
+                    continue;
+                }
+            };
+
+            if key == "entry" {
+                if config.count == MAX_ENTRIES {
+                    kprintln!("Too many boot entries, ignoring {:?}", value);
+                    break;
+                }
+                config.entries[config.count] = Entry {
+                    title: value,
+                    ..Entry::empty()
+                };
+                config.count += 1;
+                continue;
+            }
+
+            match (key, config.count.checked_sub(1)) {
+                ("timeout", _) => config.timeout = value.parse().unwrap_or(config.timeout),
+                ("default", _) => config.default = value.parse().unwrap_or(config.default),
+                ("image", Some(i)) => config.entries[i].image = value,
+                ("cmdline", Some(i)) => config.entries[i].command_line = value,
+                ("debug", Some(i)) => config.entries[i].debug = value == "1" || value == "true",
+                _ => kprintln!("Ignoring config line {:?}", line),
+            }
+        }
+
+        if config.default >= config.count {
+            config.default = 0;
+        }
+        config
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries[..self.count]
+    }
+}
+
+/// Reads the boot config from the ESP. Boot services have to still be available.
+pub fn load_config(image_handle: efi::Handle) -> Option<Config> {
+    let data = efi::read_file(image_handle, wchar!("efi\\boot\\boot.cfg"))?;
+    match core::str::from_utf8(data) {
+        Ok(text) => Some(Config::parse(text)),
+        Err(_) => {
+            kprintln!("Boot config isn't valid UTF-8!");
+            None
+        }
+    }
+}
+
+/// Shows the menu until the user picks an entry or the timeout runs out. Pressing any key stops the
+/// countdown, escape boots the default entry straight away.
+pub fn choose(config: &Config) -> Option<Entry> {
+    let entries = config.entries();
+    if entries.is_empty() {
+        return None;
+    }
+
+    let table = efi::get_system_table();
+    let console_in = table.console_in();
+    let mut console_out = table.console_out();
+    console_in.reset();
+    console_out.enable_cursor(false);
+
+    let mut selected = config.default;
+    let mut remaining = Some(config.timeout);
+
+    loop {
+        console_out.clear_screen();
+        for (i, entry) in entries.iter().enumerate() {
+            let marker = if i == selected { '>' } else { ' ' };
+            write!(console_out, "{} {}\n", marker, entry.title).ok();
+        }
+        match remaining {
+            Some(seconds) => write!(console_out, "\nBooting in {}s\n", seconds).ok(),
+            None => write!(console_out, "\nUp/Down to select, Enter to boot\n").ok(),
+        };
+
+        if remaining == Some(0) {
+            return Some(entries[selected]);
+        }
+
+        // Poll the keyboard for a second in small steps so input feels responsive
+        let mut key = None;
+        for _ in 0..10 {
+            key = console_in.read_key_stroke();
+            if key.is_some() {
+                break;
+            }
+            table.boot_services().stall(100_000);
+        }
+
+        match key {
+            Some(key) => {
+                remaining = None;
+                match (key.scan_code, key.unicode_char) {
+                    (InputKey::SCAN_UP, _) => selected = selected.saturating_sub(1),
+                    (InputKey::SCAN_DOWN, _) => selected = (selected + 1).min(entries.len() - 1),
+                    (InputKey::SCAN_ESC, _) => return Some(entries[config.default]),
+                    (_, 0x0D) => return Some(entries[selected]),
+                    _ => (),
+                }
+            }
+            None => remaining = remaining.map(|s| s - 1),
+        }
+    }
+}
+
+/// Converts an ESP path from the config to a null terminated UTF-16 string
+pub fn to_char16<'a>(string: &str, buffer: &'a mut [Char16; MAX_PATH]) -> Option<&'a [Char16]> {
+    let mut len = 0;
+    for unit in string.encode_utf16() {
+        if len == MAX_PATH - 1 {
+            return None;
+        }
+        buffer[len] = unit;
+        len += 1;
+    }
+    buffer[len] = 0;
+    Some(&buffer[..=len])
+}